@@ -19,7 +19,8 @@ fn main() {
     dbg!(
         bvh.intersect(&Ray {
             x: O,
-            d: normalize(&v(-201., 0.5, 1.))
+            d: normalize(&v(-201., 0.5, 1.)),
+            time: 0.,
         })
         .unwrap()
         .0
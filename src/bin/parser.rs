@@ -2,5 +2,9 @@ use graphics::path_tracer::obj::*;
 
 fn main() {
     let path = std::env::args().nth(1).unwrap();
-    dbg!(read_obj_file(&path).unwrap());
+    if path.ends_with(".mtl") {
+        dbg!(read_mtl_file(&path).unwrap());
+    } else {
+        dbg!(read_obj_file(&path).unwrap());
+    }
 }
@@ -1,14 +1,41 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use graphics::color::{DisplayTransform, ToneMapper};
 use graphics::math::{Triangle, B1, B2, B3, V3};
-use graphics::path_tracer::obj;
-use graphics::path_tracer::obj::ObjLine;
-use graphics::path_tracer::primitives::CupLight;
-use graphics::{math, path_tracer};
-use image::{ImageBuffer, Pixel};
-use rayon::iter::IntoParallelIterator;
-use rayon::prelude::*;
+use graphics::noise::{Fbm, Perlin, Simplex, Warp};
+use graphics::path_tracer::animation::{frame_seed, FrameRange, Keyframes};
+use graphics::path_tracer::aperture::ImageAperture;
+use graphics::path_tracer::bvh::BVHNode;
+use graphics::path_tracer::camera::{
+    Camera, CameraBuilder, EquirectangularCamera, Exposure, FisheyeCamera, OrthographicCamera,
+};
+use graphics::path_tracer::film::{
+    self, BoxFilter, Film, Filter, GaussianFilter, LanczosFilter, MitchellFilter, TentFilter,
+};
+use graphics::path_tracer::layered::{Layered, Mix};
+use graphics::path_tracer::lens::{
+    parse_lens_prescription, read_lens_file, RealisticCamera, DOUBLE_GAUSS_50MM,
+};
+use graphics::path_tracer::microfacet::{
+    Beckmann, RoughConductor, RoughDielectric, TrowbridgeReitz,
+};
+use graphics::path_tracer::obj::{read_mtl_file, ObjLine};
+use graphics::path_tracer::primitives::{CupLight, MeshTriangle};
+use graphics::path_tracer::principled::{mtl_to_materials, Principled};
+use graphics::path_tracer::render;
+use graphics::path_tracer::sampler::{
+    HaltonSampler, IndependentSampler, Sampler, SobolSampler, StratifiedSampler,
+};
+use graphics::path_tracer::texture::{
+    Checkerboard, ImageTexture, NoiseTexture, NormalMap, NormalMapped, Texture, Textured, Wrap,
+};
+use graphics::path_tracer::{Aov, BSDF};
+use graphics::post::PostEffect;
+use graphics::{color, image_io, math, path_tracer, post};
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,6 +43,9 @@ struct Args {
     #[arg(short, long, default_value_t = 512)]
     size: usize,
 
+    #[arg(long)]
+    height: Option<usize>,
+
     #[arg(short, long, default_value_t = 5)]
     antialias: u32,
 
@@ -25,6 +55,8 @@ struct Args {
     #[arg(short, long, default_value_t = false)]
     preview: bool,
 
+    /// Output image. Animations replace a run of '#' with the frame number, or add it in
+    /// front of the extension
     #[arg(short, long, default_value = "out.png")]
     out: String,
 
@@ -46,31 +78,457 @@ struct Args {
     #[arg(short, long, default_value = "monkey.obj")]
     file: String,
 
-    #[arg(long, default_value_t = 0.0001)]
-    lens_radius: f64,
+    /// 35mm equivalent focal length in millimeters
+    #[arg(long, default_value_t = 35.)]
+    focal_length: f64,
+
+    /// Vertical field of view in degrees, overrides the focal length
+    #[arg(long)]
+    fov: Option<f64>,
+
+    #[arg(long, default_value_t = 64.)]
+    f_number: f64,
+
+    /// Shutter time in seconds. When set, radiance is exposed like a real camera using the
+    /// f-number, shutter time and ISO instead of being passed through unscaled
+    #[arg(long)]
+    shutter_time: Option<f64>,
+
+    #[arg(long, default_value_t = 100.)]
+    iso: f64,
+
+    /// Seconds after each frame's time that the shutter closes
+    #[arg(long, default_value_t = 0.)]
+    shutter_close: f64,
+
+    /// Degrees per second the model turns around the vertical axis
+    #[arg(long, default_value_t = 0.)]
+    spin: f64,
+
+    /// Degrees per second the camera orbits around the model
+    #[arg(long, default_value_t = 0.)]
+    orbit: f64,
+
+    /// First frame of an animation. Frames are written to numbered files, see --out
+    #[arg(long)]
+    frame_start: Option<usize>,
+
+    /// Last frame of the animation, inclusive
+    #[arg(long)]
+    frame_end: Option<usize>,
+
+    /// Frames per second of the animation
+    #[arg(long, default_value_t = 24., value_parser = positive)]
+    fps: f64,
+
+    /// Darken the image corners with the cos^4 law
+    #[arg(long)]
+    natural_vignetting: bool,
+
+    /// Number of aperture blades, a circular aperture when not set
+    #[arg(long)]
+    aperture_blades: Option<usize>,
+
+    /// Rotation of the aperture blades in degrees
+    #[arg(long, default_value_t = 0.)]
+    aperture_rotation: f64,
+
+    /// Grayscale image used as the aperture mask, overrides the blades
+    #[arg(long)]
+    aperture_image: Option<String>,
+
+    /// Focus distance, autofocuses on the center of the image when not set
+    #[arg(long)]
+    focus_distance: Option<f64>,
+
+    /// Material of the model
+    #[arg(long, value_enum, default_value_t = MaterialKind::Diffuse)]
+    material: MaterialKind,
+
+    /// Index of refraction of glass
+    #[arg(long, default_value_t = 1.5)]
+    ior: f64,
+
+    /// Roughness of metals and frosted glass, from 0 (polished) to 1
+    #[arg(long, default_value_t = 0.3)]
+    roughness: f64,
+
+    /// Facet slope deviation of rough diffuse in degrees
+    #[arg(long, default_value_t = 20.)]
+    sigma: f64,
+
+    /// Base color of the principled material as r,g,b
+    #[arg(long, default_value = "0.8,0.8,0.8", value_parser = rgb)]
+    base_color: V3,
+
+    /// Metalness of the principled material
+    #[arg(long, default_value_t = 0.)]
+    metallic: f64,
+
+    /// Specular reflectance of the principled material, 0.5 being 4% at normal incidence
+    #[arg(long, default_value_t = 0.5)]
+    specular: f64,
+
+    /// Tints the principled specular towards the base color
+    #[arg(long, default_value_t = 0.)]
+    specular_tint: f64,
+
+    /// Clearcoat layer of the principled material
+    #[arg(long, default_value_t = 0.)]
+    clearcoat: f64,
+
+    /// Glossiness of the principled clearcoat, from 0 (satin) to 1 (gloss)
+    #[arg(long, default_value_t = 1.)]
+    clearcoat_gloss: f64,
+
+    /// Sheen of the principled material
+    #[arg(long, default_value_t = 0.)]
+    sheen: f64,
+
+    /// Tints the principled sheen towards the base color
+    #[arg(long, default_value_t = 0.5)]
+    sheen_tint: f64,
+
+    /// Transmission of the principled material
+    #[arg(long, default_value_t = 0.)]
+    transmission: f64,
+
+    /// Anisotropy of the principled material
+    #[arg(long, default_value_t = 0.)]
+    anisotropic: f64,
+
+    /// Image mapped onto the diffuse reflectance of the model, for materials with a diffuse
+    /// base
+    #[arg(long)]
+    texture: Option<String>,
+
+    /// Tangent space normal map of the model
+    #[arg(long)]
+    normal_map: Option<String>,
+
+    /// Height map bumping the model, ignored with a normal map
+    #[arg(long)]
+    bump_map: Option<String>,
+
+    /// Height of white in the bump map, in the units of the model
+    #[arg(long, default_value_t = 0.005)]
+    bump_scale: f64,
+
+    /// Share of dust on dusty gold
+    #[arg(long, default_value_t = 0.3)]
+    mix: f64,
+
+    /// Grayscale image mapped onto the share of dust on dusty gold, overrides --mix
+    #[arg(long)]
+    mix_mask: Option<String>,
+
+    /// Microfacet distribution of frosted glass
+    #[arg(long, value_enum, default_value_t = Distribution::Ggx)]
+    distribution: Distribution,
+
+    #[arg(long, value_enum, default_value_t = Projection::Perspective)]
+    projection: Projection,
+
+    /// Height of the orthographic view in world units
+    #[arg(long, default_value_t = 2.)]
+    ortho_height: f64,
+
+    /// Interpupillary distance for stereo panoramas
+    #[arg(long, default_value_t = 0.064)]
+    ipd: f64,
+
+    /// PBRT style lens prescription for the realistic projection, a 50mm double Gauss when
+    /// not set
+    #[arg(long)]
+    lens_file: Option<String>,
+
+    /// Film diagonal of the realistic projection in millimeters
+    #[arg(long, default_value_t = 35.)]
+    film_diagonal: f64,
+
+    /// Stops the realistic lens down to this aperture diameter in millimeters
+    #[arg(long)]
+    lens_aperture: Option<f64>,
+
+    #[arg(long, default_value_t = 32)]
+    tile_size: usize,
+
+    #[arg(long)]
+    time_budget: Option<f64>,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    #[arg(long, value_enum, default_value_t = SamplerKind::Sobol)]
+    sampler: SamplerKind,
+
+    #[arg(long, value_enum, default_value_t = FilterKind::Box)]
+    filter: FilterKind,
+
+    #[arg(long, default_value_t = 0.5)]
+    filter_radius: f64,
+
+    /// Extra layers written alongside the image for .exr, .hdr and .pfm output
+    #[arg(long, value_enum, value_delimiter = ',')]
+    aovs: Vec<AovKind>,
+
+    /// Exposure adjustment in stops, applied before tone mapping
+    #[arg(long, default_value_t = 0.)]
+    exposure: f64,
+
+    #[arg(long, value_enum, default_value_t = ToneMapKind::Reinhard)]
+    tone_map: ToneMapKind,
+
+    /// White point for the extended Reinhard and Hable operators
+    #[arg(long, default_value_t = 11.2)]
+    white: f64,
+
+    #[arg(long, default_value_t = false)]
+    no_dither: bool,
+
+    /// Bloom strength, 0 disables bloom
+    #[arg(long, default_value_t = 0.)]
+    bloom: f64,
+
+    #[arg(long, default_value_t = 1.)]
+    bloom_threshold: f64,
+
+    /// Bloom radius in pixels
+    #[arg(long, default_value_t = 24.)]
+    bloom_radius: f64,
+
+    /// Glare streak strength, 0 disables glare
+    #[arg(long, default_value_t = 0.)]
+    glare: f64,
+
+    #[arg(long, default_value_t = 6)]
+    glare_streaks: usize,
+
+    /// Glare streak length in pixels
+    #[arg(long, default_value_t = 40.)]
+    glare_length: f64,
+
+    #[arg(long, default_value_t = 1.)]
+    glare_threshold: f64,
+
+    /// Angle of the first glare streak in degrees, clockwise from pointing right
+    #[arg(long, default_value_t = 0.)]
+    glare_rotation: f64,
+
+    /// Darkening at the image corners, between 0 and 1
+    #[arg(long, default_value_t = 0.)]
+    vignette: f64,
+
+    /// Exponent of the vignette's growth from the center to the corners
+    #[arg(long, default_value_t = 2.)]
+    vignette_falloff: f64,
+
+    /// Red is magnified and blue shrunk by e^x around the image center
+    #[arg(long, default_value_t = 0.)]
+    chromatic_aberration: f64,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ToneMapKind {
+    Clamp,
+    Reinhard,
+    ReinhardExtended,
+    Hable,
+    Aces,
+    Agx,
+}
+
+fn post_pipeline(args: &Args) -> post::Pipeline {
+    let mut effects: Vec<Box<dyn post::PostEffect>> = Vec::new();
+    if args.chromatic_aberration != 0. {
+        effects.push(Box::new(post::ChromaticAberration {
+            strength: args.chromatic_aberration,
+        }));
+    }
+    if args.bloom > 0. {
+        effects.push(Box::new(post::Bloom {
+            threshold: args.bloom_threshold,
+            intensity: args.bloom,
+            radius: args.bloom_radius,
+        }));
+    }
+    if args.glare > 0. {
+        effects.push(Box::new(post::Glare {
+            threshold: args.glare_threshold,
+            intensity: args.glare,
+            length: args.glare_length,
+            streaks: args.glare_streaks,
+            rotation: args.glare_rotation.to_radians(),
+        }));
+    }
+    if args.vignette > 0. {
+        effects.push(Box::new(post::Vignette {
+            strength: args.vignette,
+            falloff: args.vignette_falloff,
+        }));
+    }
+    post::Pipeline { effects }
+}
+
+fn new_tone_mapper(kind: ToneMapKind, white: f64) -> Box<dyn ToneMapper> {
+    match kind {
+        ToneMapKind::Clamp => Box::new(color::Clamp),
+        ToneMapKind::Reinhard => Box::new(color::Reinhard),
+        ToneMapKind::ReinhardExtended => Box::new(color::ReinhardExtended { white }),
+        ToneMapKind::Hable => Box::new(color::Hable { white }),
+        ToneMapKind::Aces => Box::new(color::AcesFitted),
+        ToneMapKind::Agx => Box::new(color::AgX),
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AovKind {
+    Normal,
+    Depth,
+    Position,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Projection {
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+    EquirectangularStereo,
+    Realistic,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MaterialKind {
+    Diffuse,
+    RoughDiffuse,
+    Mirror,
+    Glass,
+    FrostedGlass,
+    Gold,
+    Copper,
+    Aluminium,
+    Principled,
+    // Grey and white diffuse squares over the model's uv.
+    Checker,
+    // Diffuse with domain warped noise veins in object space.
+    Marble,
+    // Grey diffuse under a varnish of --ior and --roughness.
+    Varnished,
+    // Gold under a --mix share of grey dust.
+    DustyGold,
+    // Materials from the model's mtllib, mapped onto the principled BSDF.
+    Mtl,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Distribution {
+    Ggx,
+    Beckmann,
 }
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+// `antialias` is the number of samples along each side of the pixel.
+fn new_sampler(kind: SamplerKind, antialias: usize, seed: u64) -> Box<dyn Sampler> {
+    let spp = antialias * antialias;
+    match kind {
+        SamplerKind::Independent => Box::new(IndependentSampler::new(spp, seed)),
+        SamplerKind::Stratified => {
+            Box::new(StratifiedSampler::new(antialias, antialias, true, seed))
+        }
+        SamplerKind::Halton => Box::new(HaltonSampler::new(spp, seed)),
+        SamplerKind::Sobol => Box::new(SobolSampler::new(spp, seed)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+fn new_filter(kind: FilterKind, radius: f64) -> Arc<dyn Filter> {
+    match kind {
+        FilterKind::Box => Arc::new(BoxFilter { radius }),
+        FilterKind::Tent => Arc::new(TentFilter { radius }),
+        FilterKind::Gaussian => Arc::new(GaussianFilter {
+            radius,
+            sigma: 0.5 * radius,
+        }),
+        FilterKind::Mitchell => Arc::new(MitchellFilter {
+            radius,
+            b: 1. / 3.,
+            c: 1. / 3.,
+        }),
+        FilterKind::Lanczos => Arc::new(LanczosFilter { radius, tau: 3. }),
+    }
+}
+
+// Value parser for arguments that have to be greater than zero.
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x > 0. && x.is_finite() => Ok(x),
+        Ok(_) => Err(format!("{} is not a positive number", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Value parser for colors given as r,g,b.
+fn rgb(s: &str) -> Result<V3, String> {
+    let channels = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    match channels[..] {
+        [r, g, b] if channels.iter().all(|c| *c >= 0. && c.is_finite()) => Ok(math::v(r, g, b)),
+        [_, _, _] => Err(format!("{} has negative or infinite channels", s)),
+        _ => Err(format!("{} is not an r,g,b color", s)),
+    }
+}
+
 fn main() {
     let args = Args::parse();
+    if !args.aovs.is_empty() && !image_io::is_hdr_path(&args.out) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("--aovs needs .exr, .hdr or .pfm output, not {}", args.out),
+            )
+            .exit()
+    }
     let w = args.size;
-    let h = args.size * 2 / 3;
+    let h = args.height.unwrap_or(args.size * 2 / 3);
     println!("initializing scene");
     let mut start = Instant::now();
     let grey_diffuse = path_tracer::primitives::Lambertian {
         reflectance: math::v(0.7, 0.7, 0.7),
     };
     let monke_obj = graphics::path_tracer::obj::read_obj_file(&args.file).unwrap();
-    let monke_triangles = path_tracer::primitives::obj_to_triangles(&monke_obj);
-    let mut monke_triangles_transformed: Vec<Vec<Triangle>> = Vec::new();
+    let monke_triangles = path_tracer::primitives::obj_to_mesh(&monke_obj);
+    let mut monke_triangles_transformed: Vec<Vec<MeshTriangle>> = Vec::new();
     for i in -args.replicas..=args.replicas {
         for j in -args.replicas..=args.replicas {
             let translate = 3.0 * i as f64 * B1 + 3. * j as f64 * B2;
             monke_triangles_transformed.push(
                 monke_triangles
                     .iter()
-                    .map(|math::Triangle { v0, v1, v2 }| Triangle {
-                        v0: *v0 + translate,
-                        v1: *v1 + translate,
-                        v2: *v2 + translate,
+                    .map(|t| MeshTriangle {
+                        triangle: Triangle {
+                            v0: t.triangle.v0 + translate,
+                            v1: t.triangle.v1 + translate,
+                            v2: t.triangle.v2 + translate,
+                        },
+                        ..*t
                     })
                     .collect(),
             );
@@ -83,158 +541,556 @@ fn main() {
     println!("init took {} s", start.elapsed().as_secs_f32());
     start = Instant::now();
     println!("building bvh tree");
-    let monke_object = Arc::new(graphics::path_tracer::primitives::triangles_to_solid(
-        final_monke_triangles,
-        Arc::new(grey_diffuse),
-        args.min_leaf_size,
+    // The texture replaces the grey reflectance of the materials with a diffuse base.
+    let texture = args.texture.as_ref().map(|path| {
+        if !matches!(
+            args.material,
+            MaterialKind::Diffuse
+                | MaterialKind::RoughDiffuse
+                | MaterialKind::Varnished
+                | MaterialKind::DustyGold
+        ) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--texture needs a material with a diffuse base, not {}",
+                        args.material.to_possible_value().unwrap().get_name()
+                    ),
+                )
+                .exit()
+        }
+        Arc::new(ImageTexture::open(path, Wrap::Repeat, true).unwrap())
+    });
+    let diffuse_base: Arc<dyn BSDF> = match &texture {
+        Some(image) => {
+            let image = image.clone();
+            Arc::new(Textured::new(move |hit| {
+                path_tracer::primitives::Lambertian {
+                    reflectance: image.evaluate(hit),
+                }
+            }))
+        }
+        None => Arc::new(grey_diffuse),
+    };
+    let mix_mask = args.mix_mask.as_ref().map(|path| {
+        if !matches!(args.material, MaterialKind::DustyGold) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--mix-mask needs --material dusty-gold",
+                )
+                .exit()
+        }
+        Arc::new(ImageTexture::open(path, Wrap::Repeat, false).unwrap()) as Arc<dyn Texture<f64>>
+    });
+    let sigma = args.sigma.to_radians();
+    let monke_material: Arc<dyn BSDF> = match args.material {
+        MaterialKind::Diffuse => diffuse_base,
+        MaterialKind::RoughDiffuse => match &texture {
+            Some(image) => {
+                let image = image.clone();
+                Arc::new(Textured::new(move |hit| {
+                    path_tracer::primitives::OrenNayar {
+                        reflectance: image.evaluate(hit),
+                        sigma,
+                    }
+                }))
+            }
+            None => Arc::new(path_tracer::primitives::OrenNayar {
+                reflectance: grey_diffuse.reflectance,
+                sigma,
+            }),
+        },
+        MaterialKind::Mirror => Arc::new(path_tracer::primitives::Mirror {
+            reflectance: math::v(0.9, 0.9, 0.9),
+        }),
+        MaterialKind::Glass => Arc::new(path_tracer::primitives::Dielectric::new(args.ior)),
+        MaterialKind::FrostedGlass => match args.distribution {
+            Distribution::Ggx => Arc::new(RoughDielectric::new(
+                args.ior,
+                TrowbridgeReitz::from_roughness(args.roughness),
+            )),
+            Distribution::Beckmann => Arc::new(RoughDielectric::new(
+                args.ior,
+                Beckmann::from_roughness(args.roughness),
+            )),
+        },
+        MaterialKind::Gold => Arc::new(RoughConductor::gold(args.roughness)),
+        MaterialKind::Copper => Arc::new(RoughConductor::copper(args.roughness)),
+        MaterialKind::Aluminium => Arc::new(RoughConductor::aluminium(args.roughness)),
+        MaterialKind::Principled => Arc::new(Principled {
+            base_color: args.base_color,
+            roughness: args.roughness,
+            ior: args.ior,
+            metallic: args.metallic,
+            specular: args.specular,
+            specular_tint: args.specular_tint,
+            clearcoat: args.clearcoat,
+            clearcoat_gloss: args.clearcoat_gloss,
+            sheen: args.sheen,
+            sheen_tint: args.sheen_tint,
+            transmission: args.transmission,
+            anisotropic: args.anisotropic,
+            ..Principled::default()
+        }),
+        MaterialKind::Checker => {
+            let checker = Checkerboard {
+                even: grey_diffuse.reflectance,
+                odd: math::v(0.9, 0.9, 0.9),
+                scale: 4.,
+            };
+            Arc::new(Textured::new(move |hit| {
+                path_tracer::primitives::Lambertian {
+                    reflectance: checker.evaluate(hit),
+                }
+            }))
+        }
+        MaterialKind::Marble => {
+            let veins = NoiseTexture {
+                noise: Warp {
+                    noise: Fbm::new(Perlin { seed: args.seed }, 2., 5),
+                    warp: Fbm::new(Simplex { seed: args.seed }, 1., 3),
+                    strength: 1.5,
+                },
+                start: math::v(0.25, 0.27, 0.25),
+                end: math::v(0.9, 0.9, 0.88),
+            };
+            Arc::new(Textured::new(move |hit| {
+                path_tracer::primitives::Lambertian {
+                    reflectance: veins.evaluate(hit),
+                }
+            }))
+        }
+        MaterialKind::Varnished => Arc::new(Layered::new(
+            diffuse_base,
+            args.ior,
+            args.roughness,
+            math::v(0.05, 0.1, 0.3),
+        )),
+        MaterialKind::DustyGold => Arc::new(Mix {
+            a: Arc::new(RoughConductor::gold(args.roughness)),
+            b: diffuse_base,
+            weight: args.mix,
+            mask: mix_mask,
+        }),
+        MaterialKind::Mtl => Arc::new(grey_diffuse),
+    };
+    let monke_bsdfs = match args.material {
+        MaterialKind::Mtl => model_materials(&args.file, &monke_obj, monke_material),
+        _ => vec![monke_material],
+    };
+    let normal_map = match (&args.normal_map, &args.bump_map) {
+        (Some(path), _) => Some(NormalMap::Tangent(Arc::new(
+            ImageTexture::open(path, Wrap::Repeat, false).unwrap(),
+        ))),
+        (None, Some(path)) => Some(NormalMap::Bump {
+            height: Arc::new(ImageTexture::open(path, Wrap::Repeat, false).unwrap()),
+            scale: args.bump_scale,
+        }),
+        (None, None) => None,
+    };
+    let monke_bsdfs = match normal_map {
+        Some(map) => monke_bsdfs
+            .into_iter()
+            .map(|material| -> Arc<dyn BSDF> {
+                Arc::new(NormalMapped {
+                    material,
+                    map: map.clone(),
+                })
+            })
+            .collect(),
+        None => monke_bsdfs,
+    };
+    // Every replica repeats the model's triangles in order.
+    let monke_object = Arc::new(
+        graphics::path_tracer::primitives::triangles_with_bsdfs_to_solid(
+            final_monke_triangles
+                .into_iter()
+                .zip(monke_bsdfs.iter().cycle().cloned())
+                .collect(),
+            args.min_leaf_size,
+        ),
+    );
+    let monke_transform = math::Transform {
+        mat: math::M3::new(B1, -B2, -B3),
+        trans: math::v(0., 0.15, 0.5),
+    };
+    let monke_to_world = monke_transform.invert();
+    let frames = args.frame_start.map(|start| {
+        let end = args.frame_end.unwrap_or(start);
+        if end < start {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("--frame-end {} comes before --frame-start {}", end, start),
+                )
+                .exit()
+        }
+        FrameRange {
+            start,
+            end,
+            fps: args.fps,
+        }
+    });
+    let end_time = frames.map_or(0., |f| f.end_time()) + args.shutter_close;
+    // Spin around the vertical axis through the model's origin.
+    let spin_keyframe = |time: f64| {
+        let transform = math::Transform {
+            mat: rotation_around_vertical(time * args.spin) * monke_to_world.mat,
+            trans: monke_to_world.trans,
+        };
+        (time, transform.invert())
+    };
+    let transformed_monke_object = Arc::new(BVHNode::new(
+        vec![
+            graphics::path_tracer::primitives::TransformedObject::animated(
+                monke_object,
+                math::AnimatedTransform::new(
+                    keyframe_times(end_time, args.spin, 45.)
+                        .map(spin_keyframe)
+                        .collect(),
+                ),
+            ),
+        ],
+        1,
     ));
-    let transformed_monke_object =
-        Arc::new(graphics::path_tracer::primitives::TransformedObject::new(
-            monke_object,
-            math::Transform {
-                mat: math::M3::new(B1, -B2, -B3),
-                trans: math::v(0., 0.15, 0.5),
-            },
-        ));
     println!("bvh took {} s", start.elapsed().as_secs_f32());
-    start = Instant::now();
-    let mut img2: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::new(w as u32, h as u32);
-    println!("rendering image");
-    let lens_width = 0.035;
-    let lens_height = 0.035 * 2. / 3.;
-    let focal_length = 0.035;
-    let f_num = 64.0;
-    let camera = path_tracer::Camera::new(
-        math::O,
-        B3,
-        focal_length / f_num * 0.5,
-        focal_length,
-        0.5,
-        0.5 * lens_width * B1,
-        0.5 * lens_height * B2,
+    let left_sphere_light = math::Sphere {
+        x: math::v(-20.1, 0., -15.),
+        r: 0.5,
+    };
+    let pink_light = graphics::path_tracer::primitives::Emissive {
+        emission: math::v(6400., 0., 6400.),
+    };
+    let pink_ball_obj = graphics::path_tracer::primitives::Solid {
+        bsdf: Arc::new(pink_light),
+        intersectable: Arc::new(left_sphere_light),
+    };
+    let right_sphere_light = math::Sphere {
+        x: math::v(20.1, 0., -15.),
+        r: 0.5,
+    };
+    let turquoise_light = graphics::path_tracer::primitives::Emissive {
+        emission: math::v(0., 6400., 6400.),
+    };
+    let turquoise_ball_obj = graphics::path_tracer::primitives::Solid {
+        bsdf: Arc::new(turquoise_light),
+        intersectable: Arc::new(right_sphere_light),
+    };
+
+    let top_plane = math::Plane {
+        x: math::v(0., 1.1, 0.),
+        n: math::v(0., -1., 0.),
+        s: math::v(1., 0., 0.),
+    };
+    let _top_plane_obj = graphics::path_tracer::primitives::Solid {
+        bsdf: Arc::new(grey_diffuse),
+        intersectable: Arc::new(top_plane),
+    };
+    let bottom_plane = math::Plane {
+        x: math::v(0., -1.1, 0.),
+        n: math::v(0., 1., 0.),
+        s: math::v(1., 0., 0.),
+    };
+    let _bottom_plane_obj = graphics::path_tracer::primitives::Solid {
+        bsdf: Arc::new(grey_diffuse),
+        intersectable: Arc::new(bottom_plane),
+    };
+
+    let l1 = graphics::path_tracer::primitives::SphereLight {
+        sphere: left_sphere_light,
+        e: pink_light,
+    };
+    let l2 = graphics::path_tracer::primitives::SphereLight {
+        sphere: right_sphere_light,
+        e: turquoise_light,
+    };
+
+    let combined_objects = graphics::path_tracer::primitives::Cup {
+        objects: vec![
+            Arc::new(pink_ball_obj),
+            Arc::new(turquoise_ball_obj),
+            transformed_monke_object,
+            //Arc::new(top_plane_obj),
+            //Arc::new(bottom_plane_obj),
+        ],
+    };
+
+    let scene = graphics::path_tracer::Scene {
+        object: Box::new(combined_objects),
+        light: Box::new(CupLight {
+            lights: vec![Box::new(l1), Box::new(l2)],
+        }),
+    };
+    let mut camera_builder = CameraBuilder::new(math::O, B3)
+        .up(-B2)
+        .aspect_ratio(w as f64 / h as f64)
+        .focal_length_35mm(args.focal_length)
+        .f_number(args.f_number);
+    if let Some(fov) = args.fov {
+        camera_builder = camera_builder.vertical_fov_degrees(fov);
+    }
+    if let Some(blades) = args.aperture_blades {
+        camera_builder = camera_builder.aperture_blades(blades, args.aperture_rotation);
+    }
+    if let Some(path) = &args.aperture_image {
+        camera_builder = camera_builder.aperture(Arc::new(ImageAperture::open(path).unwrap()));
+    }
+    if let Some(shutter_time) = args.shutter_time {
+        camera_builder = camera_builder.exposure(shutter_time, args.iso);
+        println!(
+            "EV100 {:.2}",
+            Exposure {
+                shutter_time,
+                iso: args.iso
+            }
+            .ev100(args.f_number)
+        );
+    }
+    camera_builder = camera_builder.natural_vignetting(args.natural_vignetting);
+    // Orbit around the same axis the model spins around.
+    let orbit_center = monke_to_world.trans;
+    let camera_keyframes = Keyframes::new(
+        keyframe_times(end_time, args.orbit, 10.)
+            .map(|time| {
+                let rotation = rotation_around_vertical(time * args.orbit);
+                let mut builder = camera_builder.clone();
+                builder.eye = orbit_center + rotation * (builder.eye - orbit_center);
+                builder.target = orbit_center + rotation * (builder.target - orbit_center);
+                builder.up = rotation * builder.up;
+                (time, builder)
+            })
+            .collect(),
     );
 
-    dbg!(&camera);
-    let pixel_vec: Vec<V3> = (0usize..(w * h))
-        .into_par_iter()
-        .map(move |x| (x % w, x / w))
-        .map(move |(x, y)| {
-            let left_sphere_light = math::Sphere {
-                x: math::v(-20.1, 0., -15.),
-                r: 0.5,
-            };
-            let pink_light = graphics::path_tracer::primitives::Emissive {
-                emission: math::v(6400., 0., 6400.),
-            };
-            let pink_ball_obj = graphics::path_tracer::primitives::Solid {
-                bsdf: Arc::new(pink_light),
-                intersectable: Arc::new(left_sphere_light),
-            };
-            let right_sphere_light = math::Sphere {
-                x: math::v(20.1, 0., -15.),
-                r: 0.5,
-            };
-            let turquoise_light = graphics::path_tracer::primitives::Emissive {
-                emission: math::v(0., 6400., 6400.),
-            };
-            let turquoise_ball_obj = graphics::path_tracer::primitives::Solid {
-                bsdf: Arc::new(turquoise_light),
-                intersectable: Arc::new(right_sphere_light),
-            };
+    let ctx = graphics::path_tracer::RenderContext {
+        imp: args.imp,
+        max_bounces: args.bounces,
+        termination_p: args.termination_p,
+        light_samples: args.light_samples,
+        preview: args.preview,
+    };
 
-            let top_plane = math::Plane {
-                x: math::v(0., 1.1, 0.),
-                n: math::v(0., -1., 0.),
-                s: math::v(1., 0., 0.),
-            };
-            let top_plane_obj = graphics::path_tracer::primitives::Solid {
-                bsdf: Arc::new(grey_diffuse),
-                intersectable: Arc::new(top_plane),
-            };
-            let bottom_plane = math::Plane {
-                x: math::v(0., -1.1, 0.),
-                n: math::v(0., 1., 0.),
-                s: math::v(1., 0., 0.),
-            };
-            let bottom_plane_obj = graphics::path_tracer::primitives::Solid {
-                bsdf: Arc::new(grey_diffuse),
-                intersectable: Arc::new(bottom_plane),
-            };
+    // The scene is shared by every frame, only the camera is rebuilt.
+    let render_at = |time: f64, seed: u64, out: &str| {
+        let builder = camera_keyframes
+            .at(time)
+            .shutter(time, time + args.shutter_close);
+        let builder = match args.focus_distance {
+            Some(distance) => builder.focus_distance(distance),
+            None => builder.autofocus(&scene),
+        };
+        let camera = new_camera(&args, &builder, w, h);
+        render_frame(&args, &scene, &ctx, camera.as_ref(), seed, out, w, h);
+    };
+    match frames {
+        None => render_at(0., args.seed, &args.out),
+        Some(frames) => {
+            for frame in frames.frames() {
+                let out = image_io::frame_path(&args.out, frame);
+                println!("frame {} -> {}", frame, out);
+                render_at(frames.time(frame), frame_seed(args.seed, frame), &out);
+            }
+        }
+    }
+}
 
-            let l1 = graphics::path_tracer::primitives::SphereLight {
-                sphere: left_sphere_light,
-                e: pink_light,
-            };
-            let l2 = graphics::path_tracer::primitives::SphereLight {
-                sphere: right_sphere_light,
-                e: turquoise_light,
-            };
+// Rotation by `degrees` around the world's vertical axis.
+fn rotation_around_vertical(degrees: f64) -> math::M3 {
+    let angle = degrees.to_radians();
+    math::M3::new(
+        math::v(angle.cos(), 0., -angle.sin()),
+        B2,
+        math::v(angle.sin(), 0., angle.cos()),
+    )
+}
 
-            let combined_objects = graphics::path_tracer::primitives::Cup {
-                objects: vec![
-                    Arc::new(pink_ball_obj),
-                    Arc::new(turquoise_ball_obj),
-                    transformed_monke_object.clone(),
-                    //Arc::new(top_plane_obj),
-                    //Arc::new(bottom_plane_obj),
-                ],
-            };
+// Evenly spaced keyframe times from 0 to `end_time`, close enough that a rotation at
+// `degrees_per_second` turns at most `max_step` degrees between them.
+fn keyframe_times(
+    end_time: f64,
+    degrees_per_second: f64,
+    max_step: f64,
+) -> impl Iterator<Item = f64> {
+    let segments = ((degrees_per_second.abs() * end_time / max_step).ceil() as usize).max(1);
+    (0..=segments).map(move |i| end_time * i as f64 / segments as f64)
+}
 
-            let scene = graphics::path_tracer::Scene {
-                object: Box::new(combined_objects),
-                light: Box::new(CupLight {
-                    lights: vec![Box::new(l1), Box::new(l2)],
-                }),
-            };
+fn new_camera(args: &Args, builder: &CameraBuilder, w: usize, h: usize) -> Box<dyn Camera> {
+    let (eye, target, up) = (builder.eye, builder.target, builder.up);
+    let shutter = builder.shutter;
+    let aspect_ratio = w as f64 / h as f64;
+    match args.projection {
+        Projection::Perspective => Box::new(builder.build()),
+        Projection::Orthographic => Box::new(
+            OrthographicCamera::look_at(eye, target, up, args.ortho_height, aspect_ratio)
+                .with_shutter(shutter),
+        ),
+        Projection::Fisheye => Box::new(
+            FisheyeCamera::look_at(eye, target, up, args.fov.unwrap_or(180.), aspect_ratio)
+                .with_shutter(shutter),
+        ),
+        Projection::Equirectangular => {
+            Box::new(EquirectangularCamera::look_at(eye, target, up, None).with_shutter(shutter))
+        }
+        Projection::EquirectangularStereo => Box::new(
+            EquirectangularCamera::look_at(eye, target, up, Some(args.ipd)).with_shutter(shutter),
+        ),
+        Projection::Realistic => {
+            let camera = match &args.lens_file {
+                Some(path) => read_lens_file(path),
+                None => parse_lens_prescription(DOUBLE_GAUSS_50MM),
+            }
+            .and_then(|elements| {
+                RealisticCamera::look_at(
+                    eye,
+                    target,
+                    up,
+                    elements,
+                    0.001 * args.film_diagonal,
+                    aspect_ratio,
+                    builder.focus_distance.unwrap_or(1.),
+                    args.lens_aperture.map(|d| 0.001 * d),
+                )
+            })
+            .unwrap_or_else(|e| {
+                Args::command()
+                    .error(ErrorKind::InvalidValue, format!("unusable lens: {}", e))
+                    .exit()
+            });
+            Box::new(camera.with_shutter(shutter))
+        }
+    }
+}
 
-            let pix_width = 2. / w as f64;
-            let loc = math::V3 {
-                x: (2. * x as f64) / w as f64 - 1.,
-                y: (-2. * y as f64) / h as f64 + 1.,
-                z: 4.,
-            };
-            let anti_aliasing = args.antialias;
-            let subpixel_width = pix_width / anti_aliasing as f64;
-            let mut pix_sum = math::O;
-            for x_jitter in 0..anti_aliasing {
-                for y_jitter in 0..anti_aliasing {
-                    let jitter = math::v(
-                        x_jitter as f64 * subpixel_width,
-                        y_jitter as f64 * subpixel_width,
-                        0.,
-                    );
-                    let subpix_loc = loc + jitter;
-                    pix_sum = pix_sum
-                        + graphics::path_tracer::estimated_total_radiance(
-                            &graphics::path_tracer::RenderContext {
-                                imp: args.imp,
-                                max_bounces: args.bounces,
-                                termination_p: args.termination_p,
-                                light_samples: args.light_samples,
-                                preview: args.preview,
-                            },
-                            &scene,
-                            &camera.sample_ray(subpix_loc.x, subpix_loc.y),
-                        )
+#[allow(clippy::too_many_arguments)]
+fn render_frame(
+    args: &Args,
+    scene: &path_tracer::Scene,
+    ctx: &path_tracer::RenderContext,
+    camera: &dyn Camera,
+    seed: u64,
+    out: &str,
+    w: usize,
+    h: usize,
+) {
+    let start = Instant::now();
+    let settings = render::RenderSettings {
+        tile_size: args.tile_size,
+        time_budget: args.time_budget.map(Duration::from_secs_f64),
+    };
+    let mut film = Film::new(w, h, new_filter(args.filter, args.filter_radius));
+    let completed = render::render(
+        &settings,
+        &mut film,
+        &AtomicBool::new(false),
+        print_progress,
+        || new_sampler(args.sampler, args.antialias as usize, seed),
+        |px, py, sampler| {
+            let (x, y) = film::raster_to_screen(w, h, px, py);
+            match camera.sample_weighted_ray(x, y, sampler) {
+                Some((weight, ray)) => {
+                    weight
+                        * graphics::path_tracer::estimated_total_radiance(ctx, scene, &ray, sampler)
                 }
+                None => math::O,
             }
-            tone_map((1.0 / (anti_aliasing as f64 * anti_aliasing as f64)) * pix_sum)
+        },
+    );
+    println!();
+    if !completed {
+        println!("render stopped early, unfinished tiles are black");
+    }
+    let radiance = post_pipeline(args).apply(w, h, &film.pixels());
+    let aovs: Vec<(&str, Vec<V3>)> = args
+        .aovs
+        .iter()
+        .map(|kind| {
+            let (name, aov) = match kind {
+                AovKind::Normal => ("normal", Aov::Normal),
+                AovKind::Depth => ("depth", Aov::Depth),
+                AovKind::Position => ("position", Aov::Position),
+            };
+            let mut aov_film = Film::new(w, h, Arc::new(BoxFilter { radius: 0.5 }));
+            render::render(
+                &settings,
+                &mut aov_film,
+                &AtomicBool::new(false),
+                |_| {},
+                || new_sampler(args.sampler, args.antialias as usize, seed),
+                |px, py, sampler| {
+                    let (x, y) = film::raster_to_screen(w, h, px, py);
+                    match camera.sample_weighted_ray(x, y, sampler) {
+                        Some((_, ray)) => graphics::path_tracer::estimated_aov(aov, scene, &ray),
+                        None => math::O,
+                    }
+                },
+            );
+            (name, aov_film.pixels())
         })
         .collect();
-    for (x, y, p) in img2.enumerate_pixels_mut() {
-        let color = pixel_vec[(x + y * (w as u32)) as usize];
-        p.channels_mut()[0] = (color.x.abs() * 255.) as u8;
-        p.channels_mut()[1] = (color.y.abs() * 255.) as u8;
-        p.channels_mut()[2] = (color.z.abs() * 255.) as u8;
-    }
     println!("Render took {} s", start.elapsed().as_secs_f32());
-    img2.save(args.out).unwrap()
+
+    if image_io::is_hdr_path(out) {
+        let mut layers = vec![image_io::Layer {
+            name: "rgb",
+            pixels: &radiance,
+        }];
+        layers.extend(
+            aovs.iter()
+                .map(|(name, pixels)| image_io::Layer { name, pixels }),
+        );
+        image_io::write_layers(out, w, h, &layers).unwrap();
+        return;
+    }
+    let display = DisplayTransform {
+        exposure: args.exposure,
+        tone_mapper: new_tone_mapper(args.tone_map, args.white),
+        dither: !args.no_dither,
+    };
+    display.to_rgb8(w, h, &radiance).save(out).unwrap()
 }
 
-fn tone_map1(x: f64) -> f64 {
-    x / (1. + x)
+fn print_progress(progress: &render::Progress) {
+    let eta = progress
+        .eta()
+        .map_or("?".to_string(), |eta| format!("{:.1} s", eta.as_secs_f32()));
+    print!(
+        "\r{:5.1}% ({}/{} tiles), eta {}   ",
+        100. * progress.fraction(),
+        progress.tiles_done,
+        progress.tiles_total,
+        eta
+    );
+    std::io::stdout().flush().unwrap();
 }
 
-fn tone_map(x: V3) -> V3 {
-    math::v(tone_map1(x.x), tone_map1(x.y), tone_map1(x.z))
+// Per face materials of a model from its material libraries, which are looked up next to the
+// model. Faces without a known material get `fallback`.
+fn model_materials(path: &str, obj: &Vec<ObjLine>, fallback: Arc<dyn BSDF>) -> Vec<Arc<dyn BSDF>> {
+    let dir = std::path::Path::new(path)
+        .parent()
+        .unwrap_or(std::path::Path::new(""));
+    let mut materials = std::collections::HashMap::new();
+    for line in obj {
+        if let ObjLine::Material(library) = line {
+            let library = dir.join(library.trim());
+            match read_mtl_file(&library.to_string_lossy()) {
+                Ok(mtl) => materials.extend(
+                    mtl_to_materials(&mtl)
+                        .into_iter()
+                        .map(|(name, m)| (name, Arc::new(m) as Arc<dyn BSDF>)),
+                ),
+                Err(e) => eprintln!("skipping material library {}: {}", library.display(), e),
+            }
+        }
+    }
+    path_tracer::primitives::obj_face_materials(obj)
+        .into_iter()
+        .map(|name| {
+            name.and_then(|name| materials.get(&name).cloned())
+                .unwrap_or_else(|| fallback.clone())
+        })
+        .collect()
 }
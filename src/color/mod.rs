@@ -0,0 +1,206 @@
+use crate::math::{mix64, v, M3, V3};
+use image::{ImageBuffer, Rgb};
+
+// Maps scene referred linear radiance onto display referred linear values in [0, 1].
+pub trait ToneMapper: Send + Sync {
+    fn map(&self, c: V3) -> V3;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Clamp;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reinhard;
+
+// Reinhard with a white point: radiance at `white` maps to 1.
+#[derive(Clone, Copy, Debug)]
+pub struct ReinhardExtended {
+    pub white: f64,
+}
+
+// John Hable's Uncharted 2 filmic curve.
+#[derive(Clone, Copy, Debug)]
+pub struct Hable {
+    pub white: f64,
+}
+
+// Stephen Hill's fit of the ACES RRT + sRGB ODT.
+#[derive(Clone, Copy, Debug)]
+pub struct AcesFitted;
+
+// Polynomial approximation of Troy Sobotka's AgX base transform.
+#[derive(Clone, Copy, Debug)]
+pub struct AgX;
+
+fn per_channel(c: V3, f: impl Fn(f64) -> f64) -> V3 {
+    v(f(c.x), f(c.y), f(c.z))
+}
+
+fn saturate(c: V3) -> V3 {
+    per_channel(c, |x| x.clamp(0., 1.))
+}
+
+impl ToneMapper for Clamp {
+    fn map(&self, c: V3) -> V3 {
+        saturate(c)
+    }
+}
+
+impl ToneMapper for Reinhard {
+    fn map(&self, c: V3) -> V3 {
+        per_channel(c, |x| x.max(0.) / (1. + x.max(0.)))
+    }
+}
+
+impl ToneMapper for ReinhardExtended {
+    fn map(&self, c: V3) -> V3 {
+        let w2 = self.white * self.white;
+        saturate(per_channel(c, |x| {
+            let x = x.max(0.);
+            x * (1. + x / w2) / (1. + x)
+        }))
+    }
+}
+
+fn hable_partial(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+impl ToneMapper for Hable {
+    fn map(&self, c: V3) -> V3 {
+        let white_scale = 1. / hable_partial(self.white);
+        saturate(per_channel(c, |x| {
+            hable_partial(2. * x.max(0.)) * white_scale
+        }))
+    }
+}
+
+impl ToneMapper for AcesFitted {
+    fn map(&self, c: V3) -> V3 {
+        // The fit's matrices are usually written row major, M3 stores columns.
+        let input = M3::new(
+            v(0.59719, 0.35458, 0.04823),
+            v(0.07600, 0.90834, 0.01566),
+            v(0.02840, 0.13383, 0.83777),
+        )
+        .t();
+        let output = M3::new(
+            v(1.60475, -0.53108, -0.07367),
+            v(-0.10208, 1.10813, -0.00605),
+            v(-0.00327, -0.07276, 1.07602),
+        )
+        .t();
+        let rrt_and_odt_fit = |x: f64| {
+            (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.4329510) + 0.238081)
+        };
+        saturate(output * per_channel(input * c, rrt_and_odt_fit))
+    }
+}
+
+impl ToneMapper for AgX {
+    fn map(&self, c: V3) -> V3 {
+        let inset = M3::new(
+            v(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+            v(0.0784335999999992, 0.878468636469772, 0.0784336),
+            v(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+        );
+        let outset = M3::new(
+            v(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+            v(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+            v(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+        );
+        let (min_ev, max_ev) = (-12.47393, 4.026069);
+        let log_encoded = per_channel(inset * c, |x| {
+            (x.max(1e-10).log2().clamp(min_ev, max_ev) - min_ev) / (max_ev - min_ev)
+        });
+        let contrast = per_channel(log_encoded, |x| {
+            let x2 = x * x;
+            let x4 = x2 * x2;
+            15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+                - 0.00232
+        });
+        // AgX's curve produces display encoded values, undo the 2.2 gamma so the sRGB
+        // encoding in DisplayTransform applies to every operator the same way.
+        saturate(per_channel(outset * contrast, |x| x.max(0.).powf(2.2)))
+    }
+}
+
+pub fn srgb_oetf(x: f64) -> f64 {
+    let x = x.clamp(0., 1.);
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1. / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_eotf(x: f64) -> f64 {
+    let x = x.clamp(0., 1.);
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Rec. 709 luminance of linear RGB.
+pub fn luminance(c: V3) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// Exposure (in stops), tone mapping and the sRGB transfer curve, followed by optional
+// triangular dithering before 8 bit quantization to hide banding in smooth gradients.
+pub struct DisplayTransform {
+    pub exposure: f64,
+    pub tone_mapper: Box<dyn ToneMapper>,
+    pub dither: bool,
+}
+
+impl DisplayTransform {
+    pub fn new(tone_mapper: Box<dyn ToneMapper>) -> Self {
+        Self {
+            exposure: 0.,
+            tone_mapper,
+            dither: true,
+        }
+    }
+
+    pub fn display_linear(&self, c: V3) -> V3 {
+        self.tone_mapper.map(2f64.powf(self.exposure) * c)
+    }
+
+    pub fn encode(&self, c: V3, x: usize, y: usize) -> [u8; 3] {
+        let srgb = per_channel(self.display_linear(c), srgb_oetf);
+        let mut ret = [0; 3];
+        for (i, channel) in [srgb.x, srgb.y, srgb.z].into_iter().enumerate() {
+            let noise = if self.dither {
+                triangular_noise(x, y, i)
+            } else {
+                0.
+            };
+            ret[i] = (channel * 255. + 0.5 + noise).clamp(0., 255.) as u8;
+        }
+        ret
+    }
+
+    pub fn to_rgb8(
+        &self,
+        width: usize,
+        height: usize,
+        pixels: &[V3],
+    ) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            Rgb(self.encode(pixels[x + y * width], x, y))
+        })
+    }
+}
+
+// Noise in (-1, 1) with a triangular distribution, in units of one quantization step.
+fn triangular_noise(x: usize, y: usize, channel: usize) -> f64 {
+    let h = mix64(mix64(mix64(x as u64) ^ y as u64) ^ channel as u64);
+    let u1 = (h >> 40) as f64 / (1u64 << 24) as f64;
+    let u2 = ((h >> 16) & 0xffffff) as f64 / (1u64 << 24) as f64;
+    u1 + u2 - 1.
+}
@@ -0,0 +1,283 @@
+use crate::math::V3;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::{error, fmt};
+
+// A named image plane of linear radiance (or an AOV such as normals or depth).
+#[derive(Clone, Copy, Debug)]
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub pixels: &'a [V3],
+}
+
+type WriteSingleLayer = fn(&str, usize, usize, &[V3]) -> Result<(), Box<dyn error::Error>>;
+
+#[derive(Debug, Clone)]
+struct UnsupportedFormat(String);
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported image format {:?}", self.0)
+    }
+}
+
+impl error::Error for UnsupportedFormat {}
+
+// Numbered path for a frame of a sequence. A run of '#' in the path is replaced by the zero
+// padded frame number, otherwise `.<frame>` goes in front of the extension.
+pub fn frame_path(path: &str, frame: usize) -> String {
+    if let Some(start) = path.find('#') {
+        let width = path[start..].chars().take_while(|c| *c == '#').count();
+        return format!(
+            "{}{:0width$}{}",
+            &path[..start],
+            frame,
+            &path[start + width..],
+            width = width
+        );
+    }
+    let ext = extension(path);
+    if ext.is_empty() {
+        return format!("{}.{:04}", path, frame);
+    }
+    Path::new(path)
+        .with_extension(format!("{:04}.{}", frame, ext))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+pub fn is_hdr_path(path: &str) -> bool {
+    matches!(extension(path).as_str(), "hdr" | "pfm" | "exr")
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+// EXR keeps every layer in one file. The single layer formats write the first layer to
+// `path` and every other layer next to it as `<stem>.<layer name>.<ext>`.
+pub fn write_layers(
+    path: &str,
+    width: usize,
+    height: usize,
+    layers: &[Layer],
+) -> Result<(), Box<dyn error::Error>> {
+    let ext = extension(path);
+    let write_single: WriteSingleLayer = match ext.as_str() {
+        "exr" => return write_exr(path, width, height, layers),
+        "hdr" => write_hdr,
+        "pfm" => write_pfm,
+        _ => return Err(Box::new(UnsupportedFormat(ext))),
+    };
+    for (i, layer) in layers.iter().enumerate() {
+        if i == 0 {
+            write_single(path, width, height, layer.pixels)?;
+        } else {
+            let layer_path = Path::new(path).with_extension(format!("{}.{}", layer.name, ext));
+            write_single(layer_path.to_str().unwrap(), width, height, layer.pixels)?;
+        }
+    }
+    Ok(())
+}
+
+fn to_rgbe(c: V3) -> [u8; 4] {
+    let v = c.x.max(c.y).max(c.z);
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256. / 2f64.powi(e);
+    [
+        (c.x.max(0.) * scale) as u8,
+        (c.y.max(0.) * scale) as u8,
+        (c.z.max(0.) * scale) as u8,
+        (e + 128) as u8,
+    ]
+}
+
+// Radiance RGBE. Scanlines are run length encoded per channel where the format allows it,
+// widths outside 8..=32767 are stored flat.
+pub fn write_hdr(
+    path: &str,
+    width: usize,
+    height: usize,
+    pixels: &[V3],
+) -> Result<(), Box<dyn error::Error>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(
+        w,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    let rle = (8..=0x7fff).contains(&width);
+    let mut channels = vec![Vec::new(); 4];
+    for y in 0..height {
+        let scanline = pixels[y * width..(y + 1) * width]
+            .iter()
+            .map(|p| to_rgbe(*p));
+        if !rle {
+            // The largest mantissa is always at least 128, so a flat scanline can't start
+            // like an encoded one.
+            for rgbe in scanline {
+                w.write_all(&rgbe)?;
+            }
+            continue;
+        }
+        for channel in channels.iter_mut() {
+            channel.clear();
+        }
+        for rgbe in scanline {
+            for (channel, value) in channels.iter_mut().zip(rgbe) {
+                channel.push(value);
+            }
+        }
+        w.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+        for channel in channels.iter() {
+            write_rle_channel(&mut w, channel)?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// Runs of three or more equal bytes become a count above 128 and the byte, anything else
+// is copied after a count of at most 128.
+fn write_rle_channel(w: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    let n = data.len();
+    let mut x = 0;
+    while x < n {
+        let mut run_start = x;
+        while run_start + 2 < n
+            && !(data[run_start] == data[run_start + 1] && data[run_start] == data[run_start + 2])
+        {
+            run_start += 1;
+        }
+        if run_start + 2 >= n {
+            run_start = n;
+        }
+        while x < run_start {
+            let len = (run_start - x).min(128);
+            w.write_all(&[len as u8])?;
+            w.write_all(&data[x..x + len])?;
+            x += len;
+        }
+        if run_start < n {
+            let mut run_end = run_start;
+            while run_end < n && data[run_end] == data[run_start] {
+                run_end += 1;
+            }
+            while x < run_end {
+                let len = (run_end - x).min(127);
+                w.write_all(&[128 + len as u8, data[run_start]])?;
+                x += len;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Little endian PFM. Scanlines are stored bottom to top.
+pub fn write_pfm(
+    path: &str,
+    width: usize,
+    height: usize,
+    pixels: &[V3],
+) -> Result<(), Box<dyn error::Error>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for p in pixels[y * width..(y + 1) * width].iter() {
+            for c in [p.x, p.y, p.z] {
+                w.write_all(&(c as f32).to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+pub fn write_exr(
+    path: &str,
+    width: usize,
+    height: usize,
+    layers: &[Layer],
+) -> Result<(), Box<dyn error::Error>> {
+    use exr::prelude::{
+        Encoding, Image, ImageAttributes, IntegerBounds, LayerAttributes, SpecificChannels, Vec2,
+        WritableImage,
+    };
+    let size = Vec2(width, height);
+    let exr_layers: Vec<_> = layers
+        .iter()
+        .map(|layer| {
+            let pixels = layer.pixels;
+            exr::prelude::Layer::new(
+                size,
+                LayerAttributes::named(layer.name),
+                Encoding::FAST_LOSSLESS,
+                SpecificChannels::rgb(move |pos: Vec2<usize>| {
+                    let p = pixels[pos.x() + pos.y() * width];
+                    (p.x as f32, p.y as f32, p.z as f32)
+                }),
+            )
+        })
+        .collect();
+    let image = Image::from_layers(
+        ImageAttributes::new(IntegerBounds::from_dimensions(size)),
+        exr_layers,
+    );
+    image.write().to_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+    use image::codecs::hdr::HdrDecoder;
+    use std::io::BufReader;
+
+    fn round_trip(width: usize, height: usize) {
+        // Long runs, short runs and noise, so every kind of packet shows up.
+        let pixels: Vec<V3> = (0..width * height)
+            .map(|i| match (i % width) / 9 % 3 {
+                _ if i % width >= 150 => math::v(2., 2., 2.),
+                0 => math::v(0.25, 1.5, 3.),
+                1 => math::v((i % 7) as f64, 0.5, (i % 3) as f64 + 0.1),
+                _ => math::v(i as f64 * 0.01, 0., 1.),
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("write_hdr_{}x{}.hdr", width, height));
+        let path = path.to_str().unwrap();
+        write_hdr(path, width, height, &pixels).unwrap();
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        let metadata = decoder.metadata();
+        assert_eq!(
+            (metadata.width, metadata.height),
+            (width as u32, height as u32)
+        );
+        let decoded = decoder.read_image_hdr().unwrap();
+        std::fs::remove_file(path).unwrap();
+        for (expected, got) in pixels.iter().zip(decoded) {
+            for (e, g) in [
+                (expected.x, got[0]),
+                (expected.y, got[1]),
+                (expected.z, got[2]),
+            ] {
+                assert!((e - g as f64).abs() <= expected.x.max(expected.y).max(expected.z) / 64.);
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_scanlines_decode() {
+        round_trip(300, 3);
+        round_trip(5, 4);
+    }
+}
@@ -1,8 +1,12 @@
 use crate::math::V3;
 
+pub mod color;
+pub mod image_io;
 pub mod marcher;
 pub mod math;
+pub mod noise;
 pub mod path_tracer;
+pub mod post;
 
 pub trait Scene {
     fn sdf(&self, x: &V3) -> f32;
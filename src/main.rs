@@ -1,6 +1,8 @@
 use clap::Parser;
-use graphics::marcher::{render, Cap, Sphere, Torus};
+use graphics::image_io;
+use graphics::marcher::{render, Cap, Displaced, Sphere, Torus};
 use graphics::math::{normalize, v, V3};
+use graphics::noise::{Fbm, Perlin};
 use image::buffer::ConvertBuffer;
 use image::{ImageBuffer, Rgb};
 use rayon::prelude::*;
@@ -14,6 +16,13 @@ struct Args {
 
     #[arg(short, long, default_value_t = 2)]
     antialias: u32,
+
+    #[arg(short, long, default_value = "out.png")]
+    out: String,
+
+    /// Height of the noise displacing the surface
+    #[arg(short, long, default_value_t = 0.)]
+    displacement: f64,
 }
 
 fn main() {
@@ -22,11 +31,10 @@ fn main() {
     println!("Starting image generation!");
     let start = Instant::now();
     let w = args.size;
-    let mut img2: ImageBuffer<image::Luma<u16>, Vec<u16>> = ImageBuffer::new(w, w);
-    img2.par_iter_mut()
-        .enumerate()
-        .map(|(i, p)| (i as u32 % w, i as u32 / w, p))
-        .for_each(move |(x, y, p)| {
+    let values: Vec<f64> = (0..w * w)
+        .into_par_iter()
+        .map(|i| (i % w, i / w))
+        .map(move |(x, y)| {
             let s = Torus {
                 center: V3 {
                     x: 0.,
@@ -45,8 +53,14 @@ fn main() {
                 center: v(0., 0., 6.),
                 radius: 0.6,
             };
-            let f = Cap {
-                renderables: vec![Box::new(s), Box::new(t)],
+            let f = Displaced {
+                renderable: Cap {
+                    renderables: vec![Box::new(s), Box::new(t)],
+                },
+                noise: Fbm::new(Perlin { seed: 0 }, 3., 4),
+                amplitude: args.displacement,
+                // The noise changes by up to about 13 per unit.
+                lipschitz: 1. + 13. * args.displacement,
             };
 
             let pix_width = 2. / w as f64;
@@ -70,9 +84,23 @@ fn main() {
                 }
             }
 
-            *p = (pix_sum / (anti_aliasing as f64 * anti_aliasing as f64) * 256.) as u16
-        });
+            pix_sum / (anti_aliasing as f64 * anti_aliasing as f64)
+        })
+        .collect();
     println!("Render took {} s", start.elapsed().as_secs_f32());
+    if image_io::is_hdr_path(&args.out) {
+        let pixels: Vec<V3> = values.iter().map(|x| v(*x, *x, *x)).collect();
+        let layer = image_io::Layer {
+            name: "luminance",
+            pixels: &pixels,
+        };
+        image_io::write_layers(&args.out, w as usize, w as usize, &[layer]).unwrap();
+        return;
+    }
+    let mut img2: ImageBuffer<image::Luma<u16>, Vec<u16>> = ImageBuffer::new(w, w);
+    for (p, x) in img2.iter_mut().zip(values) {
+        *p = (x * 256.) as u16
+    }
     let a: ImageBuffer<Rgb<u16>, Vec<u16>> = img2.convert();
-    a.save("out.png").unwrap()
+    a.save(args.out).unwrap()
 }
@@ -1,4 +1,5 @@
 use crate::math::{abs, add, dist, dot, mul, normalize, sub, Ray, B1, B2, B3, O, V3};
+use crate::noise::Noise;
 #[derive(Default)]
 pub struct Cup {
     renderables: Vec<Box<dyn Renderable>>,
@@ -25,13 +26,23 @@ pub struct Plane {
     pub axis: V3,
 }
 
+// A shape with its surface pushed out by `amplitude` times a noise. Displacement stretches
+// distances, so the field is scaled down by `lipschitz`, a bound on how fast the displaced
+// field changes, to keep the marcher from stepping through the surface.
+pub struct Displaced<R: Renderable, N: Noise> {
+    pub renderable: R,
+    pub noise: N,
+    pub amplitude: f64,
+    pub lipschitz: f64,
+}
+
 pub trait Renderable {
     fn sdf(&self, x: &V3) -> f64;
 }
 
 impl Renderable for Sphere {
     fn sdf(&self, x: &V3) -> f64 {
-        abs(&sub(&x, &self.center)) - self.radius
+        abs(&sub(x, &self.center)) - self.radius
     }
 }
 
@@ -71,6 +82,12 @@ impl Renderable for Torus {
     }
 }
 
+impl<R: Renderable, N: Noise> Renderable for Displaced<R, N> {
+    fn sdf(&self, x: &V3) -> f64 {
+        (self.renderable.sdf(x) - self.amplitude * self.noise.noise(*x)) / self.lipschitz.max(1.)
+    }
+}
+
 impl Renderable for Plane {
     fn sdf(&self, x: &V3) -> f64 {
         dot(&sub(x, &self.point), &self.axis)
@@ -78,7 +95,7 @@ impl Renderable for Plane {
 }
 
 pub fn render(s: &impl Renderable, loc: &V3) -> f64 {
-    let normalized_loc = normalize(&loc);
+    let normalized_loc = normalize(loc);
     let light = normalize(&V3 {
         x: 1.,
         y: 1.,
@@ -92,13 +109,14 @@ pub fn render(s: &impl Renderable, loc: &V3) -> f64 {
 }
 
 fn intersect(r: &impl Renderable, x: &V3, dir: &V3) -> Option<Ray> {
-    let mut y = x.clone();
+    let mut y = *x;
     for _ in 0..1000 {
         let sdf = r.sdf(&y);
         if sdf < EPS {
             return Some(Ray {
                 d: normalize(&dsdf(r, &y)),
                 x: y,
+                time: 0.,
             });
         }
         y = add(&y, &mul(sdf, dir));
@@ -115,9 +133,9 @@ fn dsdf(r: &impl Renderable, x: &V3) -> V3 {
     let dy = mul(EPS, &B2);
     let dz = mul(EPS, &B3);
 
-    let x_plus_dx = add(&dx, &x);
-    let x_plus_dy = add(&dy, &x);
-    let x_plus_dz = add(&dz, &x);
+    let x_plus_dx = add(&dx, x);
+    let x_plus_dy = add(&dy, x);
+    let x_plus_dz = add(&dz, x);
 
     let dsdx = (r.sdf(&x_plus_dx) - r.sdf(x)) / EPS;
     let dsdy = (r.sdf(&x_plus_dy) - r.sdf(x)) / EPS;
@@ -52,16 +52,182 @@ impl Transform {
     }
 }
 
+// Unit quaternion, used to interpolate rotations.
+#[derive(Clone, Copy, Debug)]
+pub struct Quaternion {
+    pub w: f64,
+    pub v: V3,
+}
+
+impl Quaternion {
+    pub fn from_rotation(m: M3) -> Quaternion {
+        let (m00, m10, m20) = (m.v0.x, m.v0.y, m.v0.z);
+        let (m01, m11, m21) = (m.v1.x, m.v1.y, m.v1.z);
+        let (m02, m12, m22) = (m.v2.x, m.v2.y, m.v2.z);
+        let trace = m00 + m11 + m22;
+        let (w, x, y, z) = if trace > 0. {
+            let s = 2. * (trace + 1.).sqrt();
+            (0.25 * s, (m21 - m12) / s, (m02 - m20) / s, (m10 - m01) / s)
+        } else if m00 > m11 && m00 > m22 {
+            let s = 2. * (1. + m00 - m11 - m22).sqrt();
+            ((m21 - m12) / s, 0.25 * s, (m01 + m10) / s, (m02 + m20) / s)
+        } else if m11 > m22 {
+            let s = 2. * (1. + m11 - m00 - m22).sqrt();
+            ((m02 - m20) / s, (m01 + m10) / s, 0.25 * s, (m12 + m21) / s)
+        } else {
+            let s = 2. * (1. + m22 - m00 - m11).sqrt();
+            ((m10 - m01) / s, (m02 + m20) / s, (m12 + m21) / s, 0.25 * s)
+        };
+        Quaternion { w, v: v(x, y, z) }
+    }
+
+    pub fn to_rotation(&self) -> M3 {
+        let (w, x, y, z) = (self.w, self.v.x, self.v.y, self.v.z);
+        M3::new(
+            v(
+                1. - 2. * (y * y + z * z),
+                2. * (x * y + w * z),
+                2. * (x * z - w * y),
+            ),
+            v(
+                2. * (x * y - w * z),
+                1. - 2. * (x * x + z * z),
+                2. * (y * z + w * x),
+            ),
+            v(
+                2. * (x * z + w * y),
+                2. * (y * z - w * x),
+                1. - 2. * (x * x + y * y),
+            ),
+        )
+    }
+
+    fn dot(&self, other: &Quaternion) -> f64 {
+        self.w * other.w + dot(&self.v, &other.v)
+    }
+
+    fn scaled(&self, s: f64) -> Quaternion {
+        Quaternion {
+            w: s * self.w,
+            v: s * self.v,
+        }
+    }
+
+    fn plus(&self, other: &Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w + other.w,
+            v: self.v + other.v,
+        }
+    }
+
+    fn normalized(&self) -> Quaternion {
+        self.scaled(1. / self.dot(self).sqrt())
+    }
+
+    // Spherical interpolation along the shorter arc.
+    pub fn slerp(&self, other: &Quaternion, t: f64) -> Quaternion {
+        let mut cos_theta = self.dot(other);
+        let other = if cos_theta < 0. {
+            cos_theta = -cos_theta;
+            other.scaled(-1.)
+        } else {
+            *other
+        };
+        if cos_theta > 0.9995 {
+            return self.scaled(1. - t).plus(&other.scaled(t)).normalized();
+        }
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        self.scaled(((1. - t) * theta).sin() / sin_theta)
+            .plus(&other.scaled((t * theta).sin() / sin_theta))
+    }
+}
+
+fn determinant(m: &M3) -> f64 {
+    dot(&m.v0, &cross(&m.v1, &m.v2))
+}
+
+// A transform keyframed over time. Like `TransformedObject`'s transform, keyframes map world
+// space to object space. They are interpolated in their object to world form, so objects
+// rotate about their own origin while it moves in a straight line.
+#[derive(Clone, Debug)]
+pub struct AnimatedTransform {
+    keyframes: Vec<(f64, Transform)>,
+}
+
+impl AnimatedTransform {
+    pub fn fixed(transform: Transform) -> AnimatedTransform {
+        AnimatedTransform {
+            keyframes: vec![(0., transform)],
+        }
+    }
+
+    // Keyframes are (time, transform) pairs. Transforms must be rigid, mirrored ones are
+    // fine as long as every keyframe is mirrored.
+    pub fn new(mut keyframes: Vec<(f64, Transform)>) -> AnimatedTransform {
+        assert!(
+            !keyframes.is_empty(),
+            "animated transform without keyframes"
+        );
+        keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        AnimatedTransform { keyframes }
+    }
+
+    pub fn keyframes(&self) -> &[(f64, Transform)] {
+        &self.keyframes
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.keyframes.len() > 1
+    }
+
+    // Clamps to the first and last keyframes outside their time range.
+    pub fn at(&self, time: f64) -> Transform {
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[self.keyframes.len() - 1];
+        if time <= first.0 {
+            return first.1;
+        }
+        if time >= last.0 {
+            return last.1;
+        }
+        let i = self.keyframes.partition_point(|(t, _)| *t <= time);
+        let (t0, a) = self.keyframes[i - 1];
+        let (t1, b) = self.keyframes[i];
+        interpolate_rigid(&a, &b, (time - t0) / (t1 - t0))
+    }
+}
+
+// Interpolates world to object transforms in their object to world form, see
+// `AnimatedTransform`.
+pub fn interpolate_rigid(a: &Transform, b: &Transform, t: f64) -> Transform {
+    let (a, b) = (a.invert(), b.invert());
+    // Quaternions only cover proper rotations, mirrored transforms are interpolated as the
+    // negated rotation.
+    let sign = if determinant(&a.mat) < 0. { -1. } else { 1. };
+    let rotation = |m: M3| M3::new(sign * m.v0, sign * m.v1, sign * m.v2);
+    let q = Quaternion::from_rotation(rotation(a.mat))
+        .slerp(&Quaternion::from_rotation(rotation(b.mat)), t);
+    Transform {
+        mat: rotation(q.to_rotation()),
+        trans: (1. - t) * a.trans + t * b.trans,
+    }
+    .invert()
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
     pub x: V3,
     pub d: V3,
+    // Moment within the shutter interval, for motion blur.
+    pub time: f64,
 }
 
 pub fn transform_ray(t: Transform, r: &Ray) -> Ray {
     Ray {
         x: t.mat * r.x + t.trans,
         d: t.mat * r.d,
+        time: r.time,
     }
 }
 
@@ -69,6 +235,7 @@ pub fn jitter_ray(r: Ray) -> Ray {
     Ray {
         x: r.x + 1. * EPS * r.d,
         d: r.d,
+        time: r.time,
     }
 }
 
@@ -113,6 +280,9 @@ impl Intersectable for Triangle {
             n: normalize(&cross(&e1, &e2)),
             s: normalize(&e1),
             t,
+            uv: (b1, b2),
+            dpdu: e1,
+            dpdv: e2,
         })
     }
 }
@@ -129,9 +299,14 @@ pub struct Intersection {
     pub n: V3,
     pub s: V3,
     pub t: f64,
+    // Surface coordinates for textures, barycentric on triangles.
+    pub uv: (f64, f64),
+    // Change in position along u and v, for bump maps and the handedness of tangent frames.
+    pub dpdu: V3,
+    pub dpdv: V3,
 }
 
-pub trait Intersectable {
+pub trait Intersectable: Send + Sync {
     fn intersect(&self, r: &Ray) -> Option<Intersection>;
 }
 
@@ -141,11 +316,17 @@ impl Intersectable for Plane {
         if t < 0.0 {
             None
         } else {
+            let x = add(&r.x, &mul(t, &r.d));
+            let offset = x - self.x;
+            let bitangent = cross(&self.n, &self.s);
             Some(Intersection {
-                x: add(&r.x, &mul(t, &r.d)),
+                x,
                 n: self.n,
                 s: self.s,
                 t,
+                uv: (dot(&offset, &self.s), dot(&offset, &bitangent)),
+                dpdu: self.s,
+                dpdv: bitangent,
             })
         }
     }
@@ -185,11 +366,20 @@ impl Intersectable for Sphere {
             v(0.0, n_unnormalized.z, -n_unnormalized.y)
         };
 
+        // Longitude and latitude, both scaled to [0, 1] with v going up like image textures.
+        let pi = std::f64::consts::PI;
+        let phi = n_normalized.y.atan2(n_normalized.x);
+        let cos_theta = n_normalized.z.clamp(-1., 1.);
+        let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+        let uv = (0.5 + phi / (2. * pi), 1. - cos_theta.acos() / pi);
         Some(Intersection {
             x: new_x,
             n: n_normalized,
             s: normalize(&s_unnormalized),
             t: t1,
+            uv,
+            dpdu: (2. * pi) * v(-n_unnormalized.y, n_unnormalized.x, 0.),
+            dpdv: (-pi * self.r) * v(cos_theta * phi.cos(), cos_theta * phi.sin(), -sin_theta),
         })
     }
 }
@@ -336,3 +526,11 @@ pub const O: V3 = V3 {
     y: 0.,
     z: 0.,
 };
+
+// splitmix64 finalizer
+pub fn mix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
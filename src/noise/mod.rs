@@ -0,0 +1,230 @@
+use crate::math;
+use crate::math::{mix64, V3};
+use std::sync::Arc;
+
+// Solid noise, a smooth random scalar field over 3D space with values in [-1, 1]. The
+// features of the basic noises are about one unit across.
+pub trait Noise: Send + Sync {
+    fn noise(&self, p: V3) -> f64;
+}
+
+impl<N: Noise + ?Sized> Noise for Arc<N> {
+    fn noise(&self, p: V3) -> f64 {
+        (**self).noise(p)
+    }
+}
+
+fn hash(x: i64, y: i64, z: i64, seed: u64) -> u64 {
+    mix64(mix64(mix64(seed ^ x as u64) ^ y as u64) ^ z as u64)
+}
+
+fn unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// One of the 12 directions to the edges of a cube, as in improved Perlin noise.
+fn gradient(h: u64, x: f64, y: f64, z: f64) -> f64 {
+    match h % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (6. * t - 15.) + 10.)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+// Perlin's improved gradient noise (2002), zero on every lattice point.
+#[derive(Clone, Copy, Debug)]
+pub struct Perlin {
+    pub seed: u64,
+}
+
+impl Noise for Perlin {
+    fn noise(&self, p: V3) -> f64 {
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - x0, p.y - y0, p.z - z0);
+        let (i, j, k) = (x0 as i64, y0 as i64, z0 as i64);
+        let corner = |di: i64, dj: i64, dk: i64| {
+            let h = hash(i + di, j + dj, k + dk, self.seed);
+            gradient(h, x - di as f64, y - dj as f64, z - dk as f64)
+        };
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let x00 = lerp(u, corner(0, 0, 0), corner(1, 0, 0));
+        let x10 = lerp(u, corner(0, 1, 0), corner(1, 1, 0));
+        let x01 = lerp(u, corner(0, 0, 1), corner(1, 0, 1));
+        let x11 = lerp(u, corner(0, 1, 1), corner(1, 1, 1));
+        lerp(w, lerp(v, x00, x10), lerp(v, x01, x11)).clamp(-1., 1.)
+    }
+}
+
+// Simplex noise (Perlin 2001, after Gustavson's reference). Sums the four corners of the
+// tetrahedron around `p` instead of the eight of a cube, without Perlin's axis aligned
+// artifacts.
+#[derive(Clone, Copy, Debug)]
+pub struct Simplex {
+    pub seed: u64,
+}
+
+impl Noise for Simplex {
+    fn noise(&self, p: V3) -> f64 {
+        const SKEW: f64 = 1. / 3.;
+        const UNSKEW: f64 = 1. / 6.;
+        let s = (p.x + p.y + p.z) * SKEW;
+        let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+        let t = (i + j + k) * UNSKEW;
+        let d0 = math::v(p.x - (i - t), p.y - (j - t), p.z - (k - t));
+        // Order the axes by the offset inside the skewed cube to find the tetrahedron.
+        let (o1, o2) = if d0.x >= d0.y {
+            if d0.y >= d0.z {
+                ((1, 0, 0), (1, 1, 0))
+            } else if d0.x >= d0.z {
+                ((1, 0, 0), (1, 0, 1))
+            } else {
+                ((0, 0, 1), (1, 0, 1))
+            }
+        } else if d0.y < d0.z {
+            ((0, 0, 1), (0, 1, 1))
+        } else if d0.x < d0.z {
+            ((0, 1, 0), (0, 1, 1))
+        } else {
+            ((0, 1, 0), (1, 1, 0))
+        };
+        let (i, j, k) = (i as i64, j as i64, k as i64);
+        let corner = |(di, dj, dk): (i64, i64, i64)| {
+            let u = (di + dj + dk) as f64 * UNSKEW;
+            let d = d0 - math::v(di as f64 - u, dj as f64 - u, dk as f64 - u);
+            let falloff = 0.6 - math::abs2(&d);
+            if falloff <= 0. {
+                return 0.;
+            }
+            let h = hash(i + di, j + dj, k + dk, self.seed);
+            falloff.powi(4) * gradient(h, d.x, d.y, d.z)
+        };
+        let sum = corner((0, 0, 0)) + corner(o1) + corner(o2) + corner((1, 1, 1));
+        (32. * sum).clamp(-1., 1.)
+    }
+}
+
+// Cellular noise (Worley 1996), the distance to the nearest of random feature points, one
+// per unit cell. Distances in [0, 1] map onto [-1, 1], cells are low in the middle.
+#[derive(Clone, Copy, Debug)]
+pub struct Worley {
+    pub seed: u64,
+}
+
+impl Noise for Worley {
+    fn noise(&self, p: V3) -> f64 {
+        let (i, j, k) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+        let mut nearest = f64::INFINITY;
+        for di in -1..=1 {
+            for dj in -1..=1 {
+                for dk in -1..=1 {
+                    let (ci, cj, ck) = (i + di, j + dj, k + dk);
+                    let h = hash(ci, cj, ck, self.seed);
+                    let feature = math::v(
+                        ci as f64 + unit(h),
+                        cj as f64 + unit(mix64(h)),
+                        ck as f64 + unit(mix64(mix64(h))),
+                    );
+                    nearest = nearest.min(math::abs2(&(feature - p)));
+                }
+            }
+        }
+        (2. * nearest.sqrt() - 1.).clamp(-1., 1.)
+    }
+}
+
+// Fractional Brownian motion: `octaves` copies of `noise`, each at `lacunarity` times the
+// frequency and `gain` times the amplitude of the one before.
+#[derive(Clone, Copy, Debug)]
+pub struct Fbm<N> {
+    pub noise: N,
+    pub frequency: f64,
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub gain: f64,
+}
+
+impl<N> Fbm<N> {
+    pub fn new(noise: N, frequency: f64, octaves: u32) -> Self {
+        Self {
+            noise,
+            frequency,
+            octaves,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+}
+
+// Octaves of a noise with their amplitudes, by `f`, normalised to keep the result in range.
+fn octaves<N: Noise>(fbm: &Fbm<N>, p: V3, f: impl Fn(f64) -> f64) -> f64 {
+    let (mut sum, mut total) = (0., 0.);
+    let (mut frequency, mut amplitude) = (fbm.frequency, 1.);
+    for octave in 0..fbm.octaves {
+        // Shifting every octave keeps their lattice points from lining up.
+        let shift = 17.31 * octave as f64;
+        let q = frequency * p + math::v(shift, -shift, 0.5 * shift);
+        sum += amplitude * f(fbm.noise.noise(q));
+        total += amplitude;
+        frequency *= fbm.lacunarity;
+        amplitude *= fbm.gain;
+    }
+    if total > 0. {
+        sum / total
+    } else {
+        0.
+    }
+}
+
+impl<N: Noise> Noise for Fbm<N> {
+    fn noise(&self, p: V3) -> f64 {
+        octaves(self, p, |n| n)
+    }
+}
+
+// Sum of the absolute octaves, with creases where the noise crosses zero (Perlin 1985).
+// The [0, 1] of the sum maps onto [-1, 1].
+#[derive(Clone, Copy, Debug)]
+pub struct Turbulence<N>(pub Fbm<N>);
+
+impl<N: Noise> Noise for Turbulence<N> {
+    fn noise(&self, p: V3) -> f64 {
+        2. * octaves(&self.0, p, f64::abs) - 1.
+    }
+}
+
+// Domain warping: `noise` read at `p` moved by `strength` times three decorrelated lookups
+// of `warp`, which swirls and stretches its features (Quilez 2002).
+#[derive(Clone, Copy, Debug)]
+pub struct Warp<N, W> {
+    pub noise: N,
+    pub warp: W,
+    pub strength: f64,
+}
+
+impl<N: Noise, W: Noise> Noise for Warp<N, W> {
+    fn noise(&self, p: V3) -> f64 {
+        let offset = math::v(
+            self.warp.noise(p),
+            self.warp.noise(p + math::v(5.2, 1.3, 7.1)),
+            self.warp.noise(p + math::v(1.7, 9.2, 3.4)),
+        );
+        self.noise.noise(p + self.strength * offset)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::math;
+use crate::math::{mix64, V3};
+use crate::path_tracer::camera::CameraBuilder;
+
+pub trait Interpolate: Clone {
+    fn interpolate(&self, other: &Self, t: f64) -> Self;
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        (1. - t) * self + t * other
+    }
+}
+
+impl Interpolate for V3 {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        (1. - t) * *self + t * *other
+    }
+}
+
+impl Interpolate for math::Transform {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        math::interpolate_rigid(self, other, t)
+    }
+}
+
+fn interpolate_option<T: Interpolate>(a: &Option<T>, b: &Option<T>, t: f64) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.interpolate(b, t)),
+        _ => a.clone(),
+    }
+}
+
+// Placement, field of view, focus and f-number are interpolated. The aperture shape,
+// exposure and shutter come from the earlier keyframe.
+impl Interpolate for CameraBuilder {
+    fn interpolate(&self, other: &Self, t: f64) -> Self {
+        Self {
+            eye: self.eye.interpolate(&other.eye, t),
+            target: self.target.interpolate(&other.target, t),
+            up: math::normalize(&self.up.interpolate(&other.up, t)),
+            vertical_fov: self.vertical_fov.interpolate(&other.vertical_fov, t),
+            aspect_ratio: self.aspect_ratio.interpolate(&other.aspect_ratio, t),
+            focus_distance: interpolate_option(&self.focus_distance, &other.focus_distance, t),
+            f_number: interpolate_option(&self.f_number, &other.f_number, t),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Easing {
+    Linear,
+    // Smoothstep between keyframes, so motion eases in and out of every keyframe.
+    Smooth,
+}
+
+// Values keyed by time in seconds, clamped to the first and last keyframes outside them.
+#[derive(Clone, Debug)]
+pub struct Keyframes<T: Interpolate> {
+    keyframes: Vec<(f64, T)>,
+    pub easing: Easing,
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    pub fn new(mut keyframes: Vec<(f64, T)>) -> Self {
+        assert!(!keyframes.is_empty(), "animation without keyframes");
+        keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self {
+            keyframes,
+            easing: Easing::Linear,
+        }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self::new(vec![(0., value)])
+    }
+
+    pub fn easing(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
+
+    pub fn keyframes(&self) -> &[(f64, T)] {
+        &self.keyframes
+    }
+
+    pub fn at(&self, time: f64) -> T {
+        let first = &self.keyframes[0];
+        let last = &self.keyframes[self.keyframes.len() - 1];
+        if time <= first.0 {
+            return first.1.clone();
+        }
+        if time >= last.0 {
+            return last.1.clone();
+        }
+        let i = self.keyframes.partition_point(|(t, _)| *t <= time);
+        let (t0, a) = &self.keyframes[i - 1];
+        let (t1, b) = &self.keyframes[i];
+        let t = (time - t0) / (t1 - t0);
+        let t = match self.easing {
+            Easing::Linear => t,
+            Easing::Smooth => t * t * (3. - 2. * t),
+        };
+        a.interpolate(b, t)
+    }
+}
+
+// An inclusive range of frames at `fps` frames per second, frame 0 being at time 0.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameRange {
+    pub start: usize,
+    pub end: usize,
+    pub fps: f64,
+}
+
+impl FrameRange {
+    pub fn frames(&self) -> impl Iterator<Item = usize> {
+        self.start..=self.end
+    }
+
+    pub fn time(&self, frame: usize) -> f64 {
+        frame as f64 / self.fps
+    }
+
+    pub fn end_time(&self) -> f64 {
+        self.time(self.end)
+    }
+}
+
+// Every frame gets its own sample pattern, and rendering a frame on its own gives the same
+// image as rendering it as part of a sequence.
+pub fn frame_seed(seed: u64, frame: usize) -> u64 {
+    mix64(seed ^ mix64(frame as u64 + 1))
+}
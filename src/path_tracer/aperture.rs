@@ -0,0 +1,186 @@
+use crate::math;
+use crate::math::V3;
+use crate::path_tracer::sample_disk;
+use crate::path_tracer::sampler::Sampler;
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use std::f64::consts::PI;
+use std::fmt;
+
+// Shape of the lens opening. Samples lie in the unit disk (z = 0) and are scaled by the lens
+// radius, so out of focus highlights take on the aperture's shape.
+pub trait Aperture: Send + Sync + fmt::Debug {
+    fn sample(&self, sampler: &mut dyn Sampler) -> V3;
+
+    // Share of the unit disk's light that gets through, which scales the image brightness.
+    fn transmission(&self) -> f64 {
+        1.
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CircularAperture;
+
+// Regular polygon with `blades` corners on the unit circle, rotated by `rotation` radians.
+#[derive(Clone, Copy, Debug)]
+pub struct PolygonalAperture {
+    pub blades: usize,
+    pub rotation: f64,
+}
+
+// Arbitrary aperture mask. The image is stretched over the square around the unit disk and
+// its brightness is the transmission at each point. Only the disk inscribed in the image is
+// part of the lens, the corners are ignored.
+#[derive(Clone, Debug)]
+pub struct ImageAperture {
+    pub width: usize,
+    pub height: usize,
+    transmission: f64,
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<Vec<f64>>,
+}
+
+impl Aperture for CircularAperture {
+    fn sample(&self, sampler: &mut dyn Sampler) -> V3 {
+        sample_disk(sampler)
+    }
+}
+
+impl Aperture for PolygonalAperture {
+    fn sample(&self, sampler: &mut dyn Sampler) -> V3 {
+        let blades = self.blades.max(3);
+        let (u, v) = sampler.get_2d();
+        // Every blade spans an equal area triangle with the center, the first dimension picks
+        // one and is then reused for the position inside it.
+        let scaled = u * blades as f64;
+        let blade = (scaled as usize).min(blades - 1);
+        let u = scaled - blade as f64;
+        let corner = |i: usize| {
+            let angle = self.rotation + 2. * PI * i as f64 / blades as f64;
+            math::v(angle.cos(), angle.sin(), 0.)
+        };
+        // Uniform point in the triangle (center, corner i, corner i + 1).
+        let su = u.sqrt();
+        (su * (1. - v)) * corner(blade) + (su * v) * corner(blade + 1)
+    }
+
+    fn transmission(&self) -> f64 {
+        let blades = self.blades.max(3) as f64;
+        blades * (2. * PI / blades).sin() / (2. * PI)
+    }
+}
+
+fn cdf(weights: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut total = 0.;
+    let mut ret: Vec<f64> = weights
+        .map(|w| {
+            total += w;
+            total
+        })
+        .collect();
+    if total > 0. {
+        ret.iter_mut().for_each(|c| *c /= total);
+    }
+    ret
+}
+
+// Picks a bucket from a normalized cdf and remaps `u` to [0, 1) inside it.
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let i = cdf.partition_point(|c| *c <= u).min(cdf.len() - 1);
+    let lo = if i == 0 { 0. } else { cdf[i - 1] };
+    let width = cdf[i] - lo;
+    let remapped = if width > 0. { (u - lo) / width } else { 0.5 };
+    (i, remapped.clamp(0., 1.))
+}
+
+impl ImageAperture {
+    // A mask that lets no light through is an error, there is nothing to sample.
+    pub fn open(path: &str) -> Result<Self, ImageError> {
+        let mask = image::open(path)?.to_luma8();
+        let (width, height) = (mask.width() as usize, mask.height() as usize);
+        let transmission = |x: usize, y: usize| {
+            let u = 2. * (x as f64 + 0.5) / width as f64 - 1.;
+            let v = 2. * (y as f64 + 0.5) / height as f64 - 1.;
+            if u * u + v * v > 1. {
+                0.
+            } else {
+                mask.get_pixel(x as u32, y as u32).0[0] as f64 / 255.
+            }
+        };
+        let rows: Vec<f64> = (0..height)
+            .map(|y| (0..width).map(|x| transmission(x, y)).sum())
+            .collect();
+        let total: f64 = rows.iter().sum();
+        if total <= 0. {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic(format!("aperture mask {} is black", path)),
+            )));
+        }
+        let column_cdfs = (0..height)
+            .map(|y| cdf((0..width).map(|x| transmission(x, y))))
+            .collect();
+        Ok(Self {
+            width,
+            height,
+            // Every pixel covers 4 / (width * height) of the square around the disk.
+            transmission: total * 4. / (width * height) as f64 / PI,
+            row_cdf: cdf(rows.into_iter()),
+            column_cdfs,
+        })
+    }
+}
+
+impl Aperture for ImageAperture {
+    fn sample(&self, sampler: &mut dyn Sampler) -> V3 {
+        let (u, v) = sampler.get_2d();
+        let (row, dy) = sample_cdf(&self.row_cdf, v);
+        let (column, dx) = sample_cdf(&self.column_cdfs[row], u);
+        // Image rows go down, the lens y axis goes up.
+        let p = math::v(
+            2. * (column as f64 + dx) / self.width as f64 - 1.,
+            1. - 2. * (row as f64 + dy) / self.height as f64,
+            0.,
+        );
+        // Pixels on the rim of the disk stick out of it a little.
+        let r = math::abs(&p);
+        if r > 1. {
+            (1. / r) * p
+        } else {
+            p
+        }
+    }
+
+    fn transmission(&self) -> f64 {
+        self.transmission
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tracer::sampler::IndependentSampler;
+
+    #[test]
+    fn white_mask_is_the_unit_disk() {
+        let path = std::env::temp_dir().join("white_aperture_mask.png");
+        image::GrayImage::from_pixel(64, 64, image::Luma([255]))
+            .save(&path)
+            .unwrap();
+        let aperture = ImageAperture::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!((aperture.transmission() - 1.).abs() < 0.01);
+        let mut sampler = IndependentSampler::new(1, 3);
+        for i in 0..1000 {
+            sampler.start_pixel_sample(0, 0, i);
+            assert!(math::abs(&aperture.sample(&mut sampler)) <= 1. + 1e-12);
+        }
+    }
+
+    #[test]
+    fn polygons_let_through_their_area() {
+        let square = PolygonalAperture {
+            blades: 4,
+            rotation: 0.,
+        };
+        assert!((square.transmission() - 2. / PI).abs() < 1e-12);
+    }
+}
@@ -53,10 +53,10 @@ impl<T: Bounded> BVHNode<T> {
                     right.push(item);
                 }
             }
-            if left.len() == 0 {
+            if left.is_empty() {
                 left.push(right.pop().unwrap())
             }
-            if right.len() == 0 {
+            if right.is_empty() {
                 right.push(left.pop().unwrap())
             }
             BVHNode {
@@ -71,6 +71,12 @@ impl<T: Bounded> BVHNode<T> {
     }
 }
 
+impl<T: Bounded> Bounded for BVHNode<T> {
+    fn get_bounds(&self) -> (V3, V3) {
+        (self.min, self.max)
+    }
+}
+
 impl<T: Bounded> Bounded for Vec<T> {
     fn get_bounds(&self) -> (V3, V3) {
         let mut state: Option<(V3, V3)> = None;
@@ -97,10 +103,10 @@ impl<T: Bounded + path_tracer::Object> BVHNode<T> {
                 for object in objects.iter() {
                     let intersection = object.intersect(r);
                     match (&ret, &intersection) {
-                        (Some((ret_intersection, _)), Some((new_intersection, _))) => {
-                            if ret_intersection.t > new_intersection.t {
-                                ret = intersection;
-                            }
+                        (Some((ret_intersection, _)), Some((new_intersection, _)))
+                            if ret_intersection.t > new_intersection.t =>
+                        {
+                            ret = intersection;
                         }
                         (None, Some(_)) => ret = intersection,
                         _ => {}
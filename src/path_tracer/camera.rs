@@ -0,0 +1,511 @@
+use crate::math;
+use crate::math::{Ray, M3, V3};
+use crate::path_tracer::aperture::{Aperture, CircularAperture, PolygonalAperture};
+use crate::path_tracer::film;
+use crate::path_tracer::sampler::Sampler;
+use crate::path_tracer::Scene;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub trait Camera: Send + Sync {
+    // `x` and `y` are screen coordinates in [-1, 1], y up. `None` when the ray is blocked
+    // inside the camera.
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray>;
+
+    fn sample_raster_ray(
+        &self,
+        width: usize,
+        height: usize,
+        px: f64,
+        py: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Ray> {
+        let (x, y) = film::raster_to_screen(width, height, px, py);
+        self.sample_ray(x, y, sampler)
+    }
+
+    // Scale on the radiance arriving at screen point (x, y): exposure and vignetting, 0
+    // where nothing is imaged.
+    fn sensor_weight(&self, _x: f64, _y: f64) -> f64 {
+        1.
+    }
+
+    // Ray and the weight of the radiance it carries. `None` when no light gets to (x, y).
+    fn sample_weighted_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<(f64, Ray)> {
+        let weight = self.sensor_weight(x, y);
+        if weight == 0. {
+            return None;
+        }
+        Some((weight, self.sample_ray(x, y, sampler)?))
+    }
+}
+
+// The interval the shutter stays open over. Rays get a uniformly distributed time in it,
+// which is what blurs moving objects.
+#[derive(Clone, Copy, Debug)]
+pub struct Shutter {
+    pub open: f64,
+    pub close: f64,
+}
+
+impl Shutter {
+    pub fn instant(time: f64) -> Self {
+        Self {
+            open: time,
+            close: time,
+        }
+    }
+
+    pub fn sample_time(&self, sampler: &mut dyn Sampler) -> f64 {
+        if self.close <= self.open {
+            return self.open;
+        }
+        self.open + sampler.get_1d() * (self.close - self.open)
+    }
+}
+
+// Shutter time in seconds and ISO film speed. Together with the f-number this scales scene
+// radiance (in cd/m^2) so that the saturation based sensor response maps onto 1, i.e.
+// `L_max = 78 / (0.65 * iso) * N^2 / t`.
+#[derive(Clone, Copy, Debug)]
+pub struct Exposure {
+    pub shutter_time: f64,
+    pub iso: f64,
+}
+
+impl Exposure {
+    pub fn scale(&self, f_number: f64) -> f64 {
+        self.shutter_time * self.iso * 0.65 / (78. * f_number * f_number)
+    }
+
+    // EV at ISO 100 of the same exposure.
+    pub fn ev100(&self, f_number: f64) -> f64 {
+        (f_number * f_number / self.shutter_time * 100. / self.iso).log2()
+    }
+}
+
+// Columns are right, up and forward. Right is `cross(forward, up)`.
+pub fn look_at_frame(eye: V3, target: V3, up: V3) -> M3 {
+    let forward = math::normalize(&(target - eye));
+    let right = math::normalize(&math::cross(&forward, &up));
+    M3::new(right, math::cross(&right, &forward), forward)
+}
+
+#[derive(Clone, Debug)]
+pub struct ThinLensCamera {
+    pub lens_origin: V3,
+    pub sensor_origin: V3,
+    pub sensor_x: V3,
+    pub sensor_y: V3,
+    pub lens_direction: V3,
+    pub lens_radius: f64,
+    pub focal_length: f64,
+    pub aperture: Arc<dyn Aperture>,
+    pub exposure_scale: f64,
+    // cos^4 falloff of the irradiance on the sensor away from the optical axis.
+    pub natural_vignetting: bool,
+    pub shutter: Shutter,
+}
+
+impl ThinLensCamera {
+    pub fn new(
+        lens_origin: V3,
+        lens_direction: V3,
+        lens_radius: f64,
+        focal_length: f64,
+        focus_distance: f64,
+        sensor_x: V3,
+        sensor_y: V3,
+    ) -> Self {
+        Self {
+            lens_origin,
+            sensor_origin: lens_origin
+                - (1.0 / (1. / focal_length - 1. / focus_distance)) * lens_direction,
+            sensor_x,
+            sensor_y,
+            lens_direction,
+            lens_radius,
+            focal_length,
+            aperture: Arc::new(CircularAperture),
+            exposure_scale: 1.,
+            natural_vignetting: false,
+            shutter: Shutter::instant(0.),
+        }
+    }
+
+    pub fn with_aperture(mut self, aperture: Arc<dyn Aperture>) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    pub fn with_exposure(mut self, exposure_scale: f64, natural_vignetting: bool) -> Self {
+        self.exposure_scale = exposure_scale;
+        self.natural_vignetting = natural_vignetting;
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    fn get_lens_basis(&self) -> M3 {
+        let basis = if math::dot(&math::B1, &self.lens_direction).abs() < 0.9 {
+            math::B1
+        } else {
+            math::B2
+        };
+        let camera_b1 = math::normalize(
+            &(basis - math::dot(&basis, &self.lens_direction) * self.lens_direction),
+        );
+        let camera_b2 = math::cross(&camera_b1, &self.lens_direction);
+        M3::new(camera_b1, camera_b2, self.lens_direction)
+    }
+    fn sample_lens_point(&self, sampler: &mut dyn Sampler) -> V3 {
+        let aperture_point = self.aperture.sample(sampler);
+        self.lens_radius * (self.get_lens_basis() * aperture_point) + self.lens_origin
+    }
+
+    fn get_lens_ray(&self, sensor_point: V3, lens_point: V3) -> Ray {
+        let l2w = self.get_lens_basis();
+        let w2l = l2w.t();
+        let normalized_sensor_point = sensor_point - self.lens_origin;
+        let normalized_lens_point = lens_point - self.lens_origin;
+        let sensor_point_lens_space = w2l * normalized_sensor_point;
+        let lens_point_lens_space = w2l * normalized_lens_point;
+        let sensor_lens_dir = lens_point_lens_space - sensor_point_lens_space;
+        let focal_lens_dir = self.focal_length / sensor_lens_dir.z * sensor_lens_dir;
+        // where the ray crosses the back focal plane, every ray through that point leaves
+        // the lens parallel to the one through the lens center
+        let focal_plane_point = lens_point_lens_space - focal_lens_dir;
+        let ray_dir = l2w * math::normalize(&(-focal_plane_point));
+        Ray {
+            x: lens_point,
+            d: ray_dir,
+            time: self.shutter.open,
+        }
+    }
+
+    fn get_sensor_point(&self, x: f64, y: f64) -> V3 {
+        self.sensor_origin + x * self.sensor_x + y * self.sensor_y
+    }
+}
+
+impl Camera for ThinLensCamera {
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let ray = self.get_lens_ray(self.get_sensor_point(x, y), self.sample_lens_point(sampler));
+        Some(Ray {
+            time: self.shutter.sample_time(sampler),
+            ..ray
+        })
+    }
+
+    fn sensor_weight(&self, x: f64, y: f64) -> f64 {
+        let scale = self.exposure_scale * self.aperture.transmission();
+        if !self.natural_vignetting {
+            return scale;
+        }
+        let to_lens = math::normalize(&(self.lens_origin - self.get_sensor_point(x, y)));
+        let cos_theta = math::dot(&to_lens, &self.lens_direction);
+        scale * cos_theta.powi(4)
+    }
+}
+
+// Full frame (36x24mm) sensor half height, used for 35mm equivalent focal lengths.
+const FULL_FRAME_HALF_HEIGHT: f64 = 0.012;
+
+// Builds a `ThinLensCamera` from a look-at description, see `look_at_frame` for the
+// orientation. Without an explicit focus distance the camera focuses on the
+// target.
+#[derive(Clone, Debug)]
+pub struct CameraBuilder {
+    pub eye: V3,
+    pub target: V3,
+    pub up: V3,
+    pub vertical_fov: f64,
+    pub aspect_ratio: f64,
+    pub focus_distance: Option<f64>,
+    pub f_number: Option<f64>,
+    pub aperture: Arc<dyn Aperture>,
+    pub exposure: Option<Exposure>,
+    pub natural_vignetting: bool,
+    pub shutter: Shutter,
+}
+
+impl CameraBuilder {
+    pub fn new(eye: V3, target: V3) -> Self {
+        Self {
+            eye,
+            target,
+            up: math::B2,
+            vertical_fov: 40f64.to_radians(),
+            aspect_ratio: 1.5,
+            focus_distance: None,
+            f_number: None,
+            aperture: Arc::new(CircularAperture),
+            exposure: None,
+            natural_vignetting: false,
+            shutter: Shutter::instant(0.),
+        }
+    }
+
+    pub fn up(mut self, up: V3) -> Self {
+        self.up = up;
+        self
+    }
+
+    pub fn vertical_fov_degrees(mut self, degrees: f64) -> Self {
+        self.vertical_fov = degrees.to_radians();
+        self
+    }
+
+    pub fn focal_length_35mm(mut self, millimeters: f64) -> Self {
+        self.vertical_fov = 2. * (FULL_FRAME_HALF_HEIGHT * 1000. / millimeters).atan();
+        self
+    }
+
+    pub fn aspect_ratio(mut self, aspect_ratio: f64) -> Self {
+        self.aspect_ratio = aspect_ratio;
+        self
+    }
+
+    pub fn focus_distance(mut self, distance: f64) -> Self {
+        self.focus_distance = Some(distance);
+        self
+    }
+
+    // Focuses on whatever the center of the image sees, falling back to the target distance.
+    pub fn autofocus(mut self, scene: &Scene) -> Self {
+        let ray = Ray {
+            x: self.eye,
+            d: self.forward(),
+            time: self.shutter.open,
+        };
+        self.focus_distance = scene.object.intersect(&ray).map(|(i, _)| i.t);
+        self
+    }
+
+    // `None` gives a pinhole camera.
+    pub fn f_number(mut self, f_number: f64) -> Self {
+        self.f_number = Some(f_number);
+        self
+    }
+
+    pub fn aperture(mut self, aperture: Arc<dyn Aperture>) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    pub fn aperture_blades(self, blades: usize, rotation_degrees: f64) -> Self {
+        self.aperture(Arc::new(PolygonalAperture {
+            blades,
+            rotation: rotation_degrees.to_radians(),
+        }))
+    }
+
+    // Without an exposure film radiance is left unscaled. A pinhole camera is exposed as if
+    // it were at f/1.
+    pub fn exposure(mut self, shutter_time: f64, iso: f64) -> Self {
+        self.exposure = Some(Exposure { shutter_time, iso });
+        self
+    }
+
+    pub fn natural_vignetting(mut self, enabled: bool) -> Self {
+        self.natural_vignetting = enabled;
+        self
+    }
+
+    pub fn shutter(mut self, open: f64, close: f64) -> Self {
+        self.shutter = Shutter { open, close };
+        self
+    }
+
+    pub fn exposure_scale(&self) -> f64 {
+        self.exposure
+            .map_or(1., |e| e.scale(self.f_number.unwrap_or(1.)))
+    }
+
+    fn forward(&self) -> V3 {
+        math::normalize(&(self.target - self.eye))
+    }
+
+    pub fn focal_length(&self) -> f64 {
+        FULL_FRAME_HALF_HEIGHT / (0.5 * self.vertical_fov).tan()
+    }
+
+    pub fn build(&self) -> ThinLensCamera {
+        let frame = look_at_frame(self.eye, self.target, self.up);
+        let (right, up, forward) = (frame.v0, frame.v1, frame.v2);
+        let focal_length = self.focal_length();
+        let focus_distance = self
+            .focus_distance
+            .unwrap_or_else(|| math::dist(&self.target, &self.eye))
+            .max(1.01 * focal_length);
+        let lens_radius = self.f_number.map_or(0., |n| 0.5 * focal_length / n);
+        let exposure_scale = self.exposure_scale();
+        // Size the sensor from the actual sensor distance so focusing keeps the field of view.
+        let sensor_distance = 1. / (1. / focal_length - 1. / focus_distance);
+        let half_height = sensor_distance * (0.5 * self.vertical_fov).tan();
+        let half_width = half_height * self.aspect_ratio;
+        // The image on the sensor is upside down and mirrored.
+        ThinLensCamera::new(
+            self.eye,
+            forward,
+            lens_radius,
+            focal_length,
+            focus_distance,
+            -half_width * right,
+            -half_height * up,
+        )
+        .with_aperture(self.aperture.clone())
+        .with_exposure(exposure_scale, self.natural_vignetting)
+        .with_shutter(self.shutter)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OrthographicCamera {
+    pub origin: V3,
+    pub frame: M3,
+    pub half_width: f64,
+    pub half_height: f64,
+    pub shutter: Shutter,
+}
+
+impl OrthographicCamera {
+    // `height` is the extent of the view in world units.
+    pub fn look_at(eye: V3, target: V3, up: V3, height: f64, aspect_ratio: f64) -> Self {
+        Self {
+            origin: eye,
+            frame: look_at_frame(eye, target, up),
+            half_width: 0.5 * height * aspect_ratio,
+            half_height: 0.5 * height,
+            shutter: Shutter::instant(0.),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        Some(Ray {
+            x: self.origin
+                + (x * self.half_width) * self.frame.v0
+                + (y * self.half_height) * self.frame.v1,
+            d: self.frame.v2,
+            time: self.shutter.sample_time(sampler),
+        })
+    }
+}
+
+// Equidistant fisheye: the angle from the view axis grows linearly with the distance from
+// the image center, reaching `fov / 2` at the top and bottom edges. Outside that circle the
+// image is black.
+#[derive(Clone, Debug)]
+pub struct FisheyeCamera {
+    pub origin: V3,
+    pub frame: M3,
+    pub fov: f64,
+    pub aspect_ratio: f64,
+    pub shutter: Shutter,
+}
+
+impl FisheyeCamera {
+    pub fn look_at(eye: V3, target: V3, up: V3, fov_degrees: f64, aspect_ratio: f64) -> Self {
+        Self {
+            origin: eye,
+            frame: look_at_frame(eye, target, up),
+            fov: fov_degrees.to_radians(),
+            aspect_ratio,
+            shutter: Shutter::instant(0.),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let u = x * self.aspect_ratio;
+        let r = (u * u + y * y).sqrt();
+        let theta = (0.5 * r * self.fov).min(PI);
+        let phi = y.atan2(u);
+        let local = math::v(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+        Some(Ray {
+            x: self.origin,
+            d: self.frame * local,
+            time: self.shutter.sample_time(sampler),
+        })
+    }
+
+    fn sensor_weight(&self, x: f64, y: f64) -> f64 {
+        let u = x * self.aspect_ratio;
+        if u * u + y * y > 1. {
+            0.
+        } else {
+            1.
+        }
+    }
+}
+
+// Full 360x180 degree latitude-longitude panorama centered on the view direction. With an
+// interpupillary distance set it renders omni-directional stereo, left eye on top and right
+// eye on the bottom half of the image.
+#[derive(Clone, Debug)]
+pub struct EquirectangularCamera {
+    pub origin: V3,
+    pub frame: M3,
+    pub stereo_ipd: Option<f64>,
+    pub shutter: Shutter,
+}
+
+impl EquirectangularCamera {
+    pub fn look_at(eye: V3, target: V3, up: V3, stereo_ipd: Option<f64>) -> Self {
+        Self {
+            origin: eye,
+            frame: look_at_frame(eye, target, up),
+            stereo_ipd,
+            shutter: Shutter::instant(0.),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (y, eye_offset) = match self.stereo_ipd {
+            None => (y, 0.),
+            Some(ipd) if y >= 0. => (2. * y - 1., -0.5 * ipd),
+            Some(ipd) => (2. * y + 1., 0.5 * ipd),
+        };
+        let longitude = x * PI;
+        let latitude = 0.5 * y * PI;
+        let local = math::v(
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            latitude.cos() * longitude.cos(),
+        );
+        // Each eye sits on a circle around the origin, perpendicular to the view direction.
+        let offset = math::v(longitude.cos(), 0., -longitude.sin());
+        Some(Ray {
+            x: self.origin + eye_offset * (self.frame * offset),
+            d: self.frame * local,
+            time: self.shutter.sample_time(sampler),
+        })
+    }
+}
@@ -0,0 +1,248 @@
+use crate::math;
+use crate::math::V3;
+use crate::path_tracer::render::Tile;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub trait Filter: Send + Sync {
+    fn radius(&self) -> f64;
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub sigma: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LanczosFilter {
+    pub radius: f64,
+    pub tau: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        if x.abs() <= self.radius && y.abs() <= self.radius {
+            1.
+        } else {
+            0.
+        }
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (1. - x.abs() / self.radius).max(0.) * (1. - y.abs() / self.radius).max(0.)
+    }
+}
+
+impl GaussianFilter {
+    fn gaussian_1d(&self, x: f64) -> f64 {
+        let g = |x: f64| (-x * x / (2. * self.sigma * self.sigma)).exp();
+        (g(x) - g(self.radius)).max(0.)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian_1d(x) * self.gaussian_1d(y)
+    }
+}
+
+impl MitchellFilter {
+    fn mitchell_1d(&self, x: f64) -> f64 {
+        let (b, c) = (self.b, self.c);
+        let x = x.abs();
+        if x <= 1. {
+            ((12. - 9. * b - 6. * c) * x * x * x
+                + (-18. + 12. * b + 6. * c) * x * x
+                + (6. - 2. * b))
+                / 6.
+        } else if x <= 2. {
+            ((-b - 6. * c) * x * x * x
+                + (6. * b + 30. * c) * x * x
+                + (-12. * b - 48. * c) * x
+                + (8. * b + 24. * c))
+                / 6.
+        } else {
+            0.
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell_1d(2. * x / self.radius) * self.mitchell_1d(2. * y / self.radius)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl LanczosFilter {
+    fn windowed_sinc(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            0.
+        } else {
+            sinc(x) * sinc(x / self.tau)
+        }
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.windowed_sinc(x) * self.windowed_sinc(y)
+    }
+}
+
+// Maps continuous raster coordinates (origin top left, y down) onto [-1, 1] screen
+// coordinates (y up). Both axes are normalized independently, so the camera's sensor has
+// to match the film's aspect ratio.
+pub fn raster_to_screen(width: usize, height: usize, px: f64, py: f64) -> (f64, f64) {
+    (2. * px / width as f64 - 1., 1. - 2. * py / height as f64)
+}
+
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Arc<dyn Filter>,
+    sums: Vec<V3>,
+    weights: Vec<f64>,
+}
+
+// The part of the film a tile can splat into: the tile's pixels plus a filter radius border.
+pub struct FilmTile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+    filter: Arc<dyn Filter>,
+    sums: Vec<V3>,
+    weights: Vec<f64>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Arc<dyn Filter>) -> Self {
+        Self {
+            width,
+            height,
+            filter,
+            sums: vec![math::O; width * height],
+            weights: vec![0.; width * height],
+        }
+    }
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+
+    pub fn tile(&self, tile: &Tile) -> FilmTile {
+        let r = self.filter.radius().ceil() as usize;
+        let x0 = tile.x0.saturating_sub(r);
+        let y0 = tile.y0.saturating_sub(r);
+        let x1 = (tile.x1 + r).min(self.width);
+        let y1 = (tile.y1 + r).min(self.height);
+        let size = (x1 - x0) * (y1 - y0);
+        FilmTile {
+            x0,
+            y0,
+            x1,
+            y1,
+            filter: self.filter.clone(),
+            sums: vec![math::O; size],
+            weights: vec![0.; size],
+        }
+    }
+
+    pub fn merge_tile(&mut self, tile: FilmTile) {
+        let tile_width = tile.x1 - tile.x0;
+        for (i, (sum, weight)) in tile.sums.into_iter().zip(tile.weights).enumerate() {
+            let x = tile.x0 + i % tile_width;
+            let y = tile.y0 + i / tile_width;
+            let index = x + y * self.width;
+            self.sums[index] = self.sums[index] + sum;
+            self.weights[index] += weight;
+        }
+    }
+
+    pub fn pixels(&self) -> Vec<V3> {
+        self.sums
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(sum, weight)| {
+                if *weight == 0. {
+                    math::O
+                } else {
+                    (1. / weight) * *sum
+                }
+            })
+            .collect()
+    }
+}
+
+impl FilmTile {
+    pub fn add_sample(&mut self, px: f64, py: f64, radiance: V3) {
+        let r = self.filter.radius();
+        let min_x = ((px - 0.5 - r).ceil().max(self.x0 as f64)) as usize;
+        let min_y = ((py - 0.5 - r).ceil().max(self.y0 as f64)) as usize;
+        let max_x = ((px - 0.5 + r).floor() + 1.).min(self.x1 as f64);
+        let max_y = ((py - 0.5 + r).floor() + 1.).min(self.y1 as f64);
+        if max_x <= 0. || max_y <= 0. {
+            return;
+        }
+        let tile_width = self.x1 - self.x0;
+        for y in min_y..max_y as usize {
+            for x in min_x..max_x as usize {
+                let weight = self
+                    .filter
+                    .evaluate(x as f64 + 0.5 - px, y as f64 + 0.5 - py);
+                let index = (x - self.x0) + (y - self.y0) * tile_width;
+                self.sums[index] = self.sums[index] + weight * radiance;
+                self.weights[index] += weight;
+            }
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use crate::color::luminance;
+use crate::math;
+use crate::math::{Intersection, V3};
+use crate::path_tracer::microfacet::{MicrofacetDistribution, TrowbridgeReitz};
+use crate::path_tracer::sampler::{IndependentSampler, Sampler};
+use crate::path_tracer::texture::Texture;
+use crate::path_tracer::{
+    flip_z, fresnel_dielectric, outgoing, reflect, sample_cosine_hemisphere, BSDFSample, BSDF,
+};
+use std::f64::consts::PI;
+use std::sync::{Arc, OnceLock};
+
+// Directions used to estimate the albedo of a layer's base, per incident angle.
+const ALBEDO_SAMPLES: usize = 256;
+// Incident angles at which a layer's trapped light is tabulated.
+const TRAPPED_ANGLES: usize = 16;
+
+// Blend of two materials, `weight` being the share of `b`. Sampling picks one of them by
+// weight, so dusty or worn surfaces cost no more than either material.
+#[derive(Clone)]
+pub struct Mix {
+    pub a: Arc<dyn BSDF>,
+    pub b: Arc<dyn BSDF>,
+    pub weight: f64,
+    // Share of `b` read at every hit instead of `weight`, e.g. where dust settles.
+    pub mask: Option<Arc<dyn Texture<f64>>>,
+}
+
+impl BSDF for Mix {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        (1. - self.weight) * self.a.bsdf(wo, wi) + self.weight * self.b.bsdf(wo, wi)
+    }
+
+    fn radiance(&self, wo: V3) -> V3 {
+        (1. - self.weight) * self.a.radiance(wo) + self.weight * self.b.radiance(wo)
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        (1. - self.weight) * self.a.pdf(wo, wi) + self.weight * self.b.pdf(wo, wi)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.a.is_specular() && self.b.is_specular()
+    }
+
+    fn at(&self, hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        let (a, b) = (self.a.at(hit), self.b.at(hit));
+        if a.is_none() && b.is_none() && self.mask.is_none() {
+            return None;
+        }
+        let weight = match &self.mask {
+            Some(mask) => mask.evaluate(hit).clamp(0., 1.),
+            None => self.weight,
+        };
+        Some(Arc::new(Mix {
+            a: a.unwrap_or_else(|| self.a.clone()),
+            b: b.unwrap_or_else(|| self.b.clone()),
+            weight,
+            mask: None,
+        }))
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (chosen, share) = if sampler.get_1d() < self.weight {
+            (&self.b, self.weight)
+        } else {
+            (&self.a, 1. - self.weight)
+        };
+        let sample = chosen.sample_f(wo, sampler);
+        // A delta lobe can't be reached by the other material, only scale it.
+        if sample.specular || sample.pdf == 0. {
+            return BSDFSample {
+                pdf: share * sample.pdf,
+                f: share * sample.f,
+                ..sample
+            };
+        }
+        BSDFSample {
+            wi: sample.wi,
+            pdf: self.pdf(wo, sample.wi),
+            f: self.bsdf(wo, sample.wi),
+            specular: false,
+        }
+    }
+}
+
+// Dielectric coating over any base (varnish, car paint, glazed ceramics), after Weidlich and
+// Wilkie 2007. Light refracts into the coat, is absorbed on its way down and back up, and
+// reflects off the base. What the coat reflects back inside bounces between base and coat
+// until it escapes, spread out diffusely.
+#[derive(Clone)]
+pub struct Layered {
+    pub base: Arc<dyn BSDF>,
+    pub ior: f64,
+    pub distribution: TrowbridgeReitz,
+    // Optical depth of the coat at normal incidence, per channel.
+    pub absorption: V3,
+    tables: Arc<Tables>,
+    // Reflectance of the base relative to the one the tables were estimated for.
+    tint: V3,
+    // Light escaping after bounces inside the coat, per unit of trapped light.
+    escaping: V3,
+    // Tables of the first base resolved at a hit, with its probed reflectance. A base that
+    // varies over the surface reuses them, scaled by its tint.
+    reference: Arc<OnceLock<(Arc<Tables>, V3)>>,
+}
+
+// What the coat traps of the light a base reflects.
+#[derive(Debug)]
+struct Tables {
+    // Share of the light entering the coat that the base reflects back into it, tabulated
+    // by the cosine of the incident direction outside.
+    trapped: Vec<V3>,
+    // The base's albedo for diffuse light inside, after absorption, and the coat's diffuse
+    // reflectance from either side.
+    albedo: V3,
+    internal: f64,
+    external: f64,
+    // A delta base reflects trapped light back at the same angle, `sample_f` sums that.
+    specular: bool,
+}
+
+impl Tables {
+    fn new(base: &dyn BSDF, ior: f64, absorption: V3) -> Self {
+        let mut sampler = IndependentSampler::new(ALBEDO_SAMPLES, 0);
+        let trapped = (0..TRAPPED_ANGLES)
+            .map(|k| {
+                let cos = (k as f64 + 0.5) / TRAPPED_ANGLES as f64;
+                let wo = refract_in(math::v((1. - cos * cos).sqrt(), 0., cos), ior);
+                let mut trapped = math::O;
+                for i in 0..ALBEDO_SAMPLES {
+                    sampler.start_pixel_sample(k, 0, i);
+                    let sample = base.sample_f(-wo, &mut sampler);
+                    if sample.pdf > 0. && sample.wi.z > 0. {
+                        let reflected = fresnel_dielectric(sample.wi.z, ior, 1.);
+                        trapped = trapped + (reflected * sample.wi.z / sample.pdf) * sample.f;
+                    }
+                }
+                (1. / ALBEDO_SAMPLES as f64) * trapped
+            })
+            .collect();
+        let mut albedo = math::O;
+        let (mut internal, mut external) = (0., 0.);
+        for i in 0..ALBEDO_SAMPLES {
+            sampler.start_pixel_sample(TRAPPED_ANGLES, 0, i);
+            let (_, w) = sample_cosine_hemisphere(&mut sampler);
+            internal += fresnel_dielectric(w.z, ior, 1.);
+            external += fresnel_dielectric(w.z, 1., ior);
+            let sample = base.sample_f(-w, &mut sampler);
+            if sample.pdf > 0. && sample.wi.z > 0. {
+                albedo = albedo + (sample.wi.z / sample.pdf) * sample.f;
+            }
+        }
+        let n = ALBEDO_SAMPLES as f64;
+        Self {
+            trapped,
+            albedo: (1. / n) * albedo * exp(-2. * absorption),
+            internal: internal / n,
+            external: external / n,
+            specular: base.is_specular(),
+        }
+    }
+
+    // Light escaping after bounces inside the coat, for a base `tint` times as reflective.
+    // Geometric series of bounces between base and coat, normalised so that the light
+    // leaving in a cosine distribution through the coat integrates to the escaping share.
+    fn escaping(&self, tint: V3) -> V3 {
+        if self.specular {
+            return math::O;
+        }
+        let escaping = |a: f64| {
+            let a = a.min(1.);
+            a * (1. - self.internal) / ((1. - a * self.internal) * (1. - self.external))
+        };
+        let albedo = tint * self.albedo;
+        math::v(escaping(albedo.x), escaping(albedo.y), escaping(albedo.z))
+    }
+}
+
+// Reflectance of a base in a fixed pair of directions, to compare it against another one.
+fn probe(base: &dyn BSDF) -> V3 {
+    let w = math::v(0.6, 0., 0.8);
+    base.bsdf(-w, math::v(-w.x, -w.y, w.z))
+}
+
+fn exp(v: V3) -> V3 {
+    math::v(v.x.exp(), v.y.exp(), v.z.exp())
+}
+
+// Direction inside a coat of index `ior` for `w` outside it, both pointing away from the base.
+fn refract_in(w: V3, ior: f64) -> V3 {
+    let (x, y) = (w.x / ior, w.y / ior);
+    math::v(x, y, (1. - x * x - y * y).max(0.).sqrt())
+}
+
+fn refract_out(w: V3, ior: f64) -> Option<V3> {
+    let (x, y) = (w.x * ior, w.y * ior);
+    let z2 = 1. - x * x - y * y;
+    if z2 <= 0. {
+        return None;
+    }
+    Some(math::v(x, y, z2.sqrt()))
+}
+
+impl Layered {
+    pub fn new(base: Arc<dyn BSDF>, ior: f64, roughness: f64, absorption: V3) -> Self {
+        let tables = Arc::new(Tables::new(base.as_ref(), ior, absorption));
+        let tint = math::v(1., 1., 1.);
+        Self {
+            base,
+            ior,
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            absorption,
+            escaping: tables.escaping(tint),
+            tables,
+            tint,
+            reference: Arc::new(OnceLock::new()),
+        }
+    }
+
+    // Trapped light diffused by multiple bounces, for light arriving from `wo`.
+    fn multiple(&self, wo: V3) -> V3 {
+        let x = (wo.z * TRAPPED_ANGLES as f64 - 0.5).clamp(0., (TRAPPED_ANGLES - 1) as f64);
+        let k = (x as usize).min(TRAPPED_ANGLES - 2);
+        let t = x - k as f64;
+        let trapped = (1. - t) * self.tables.trapped[k] + t * self.tables.trapped[k + 1];
+        self.tint * trapped * self.escaping
+    }
+
+    fn coat_probability(&self, wo: V3) -> f64 {
+        fresnel_dielectric(wo.z, 1., self.ior)
+    }
+
+    // Share of the base branch spent on the diffuse light escaping after multiple bounces.
+    fn multiple_probability(&self, wo: V3) -> f64 {
+        let m = luminance(self.multiple(wo)).max(0.);
+        m / (1. + m)
+    }
+
+    // Share of light that gets through the coat into `wi` and back out into `wo`.
+    fn through_coat(&self, wo: V3, wi: V3) -> V3 {
+        let (wo_t, wi_t) = (refract_in(wo, self.ior), refract_in(wi, self.ior));
+        let transmission = (1. - fresnel_dielectric(wo.z, 1., self.ior))
+            * (1. - fresnel_dielectric(wi.z, 1., self.ior));
+        transmission * exp(-(1. / wo_t.z + 1. / wi_t.z) * self.absorption)
+    }
+
+    // Radiance is concentrated by ior squared inside the coat and spread out again on the
+    // way out, but the base sees the outside solid angle compressed into a smaller cone.
+    fn f_base(&self, wo: V3, wi: V3) -> V3 {
+        let (wo_t, wi_t) = (refract_in(wo, self.ior), refract_in(wi, self.ior));
+        let through = self.through_coat(wo, wi);
+        (1. / (self.ior * self.ior)) * through * self.base.bsdf(-wo_t, wi_t)
+    }
+
+    // The escaping light leaves in a cosine distribution weighted by the coat's transmission.
+    fn f_multiple(&self, wo: V3, wi: V3) -> V3 {
+        (1. / PI) * self.through_coat(wo, wi) * self.multiple(wo)
+    }
+
+    fn f_coat(&self, wo: V3, wi: V3) -> f64 {
+        if self.distribution.effectively_smooth() {
+            return 0.;
+        }
+        let wm = math::normalize(&(wo + wi));
+        let fresnel = fresnel_dielectric(math::dot(&wo, &wm), 1., self.ior);
+        self.distribution.d(wm) * self.distribution.g(wo, wi) * fresnel / (4. * wo.z * wi.z)
+    }
+
+    fn pdf_local(&self, wo: V3, wi: V3) -> f64 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return 0.;
+        }
+        let p_coat = self.coat_probability(wo);
+        let coat = if self.distribution.effectively_smooth() {
+            0.
+        } else {
+            let wm = math::normalize(&(wo + wi));
+            self.distribution.pdf(wo, wm) / (4. * math::dot(&wo, &wm))
+        };
+        let (wo_t, wi_t) = (refract_in(wo, self.ior), refract_in(wi, self.ior));
+        // Solid angles outside are spread over the coat's smaller cone inside.
+        let jacobian = wi.z / (self.ior * self.ior * wi_t.z);
+        let p_multiple = self.multiple_probability(wo);
+        let base =
+            (1. - p_multiple) * self.base.pdf(-wo_t, wi_t) * jacobian + p_multiple * wi.z / PI;
+        p_coat * coat + (1. - p_coat) * base
+    }
+}
+
+impl BSDF for Layered {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        let (wo, flip) = outgoing(wo);
+        let wi = flip_z(wi, flip);
+        if wo.z <= 0. || wi.z <= 0. {
+            return math::O;
+        }
+        let coat = self.f_coat(wo, wi);
+        math::v(coat, coat, coat) + self.f_base(wo, wi) + self.f_multiple(wo, wi)
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        let (wo, flip) = outgoing(wo);
+        self.pdf_local(wo, flip_z(wi, flip))
+    }
+
+    // Estimating the tables at every hit would dominate the render, textures usually only
+    // change how much the base reflects.
+    fn at(&self, hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        let base = self.base.at(hit)?;
+        let probed = probe(base.as_ref());
+        let (tables, reference) = self.reference.get_or_init(|| {
+            let tables = Tables::new(base.as_ref(), self.ior, self.absorption);
+            (Arc::new(tables), probed)
+        });
+        let ratio = |p: f64, r: f64| if r > 0. { p / r } else { 1. };
+        let tint = math::v(
+            ratio(probed.x, reference.x),
+            ratio(probed.y, reference.y),
+            ratio(probed.z, reference.z),
+        );
+        Some(Arc::new(Layered {
+            base,
+            escaping: tables.escaping(tint),
+            tables: tables.clone(),
+            tint,
+            ..self.clone()
+        }))
+    }
+
+    fn perturb(&self, hit: &mut Intersection) {
+        self.base.perturb(hit);
+    }
+
+    fn sample_f(&self, wo_ray: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (wo, flip) = outgoing(wo_ray);
+        let p_coat = self.coat_probability(wo);
+        let invalid = BSDFSample {
+            wi: math::B3,
+            pdf: 0.,
+            f: math::O,
+            specular: false,
+        };
+        if sampler.get_1d() < p_coat {
+            if self.distribution.effectively_smooth() {
+                let wi = math::v(-wo.x, -wo.y, wo.z);
+                return BSDFSample {
+                    wi: flip_z(wi, flip),
+                    pdf: p_coat,
+                    f: (p_coat / wi.z) * math::v(1., 1., 1.),
+                    specular: true,
+                };
+            }
+            let wi = reflect(wo, self.distribution.sample_wm(wo, sampler.get_2d()));
+            if wi.z <= 0. {
+                return invalid;
+            }
+            return BSDFSample {
+                wi: flip_z(wi, flip),
+                pdf: self.pdf_local(wo, wi),
+                f: self.bsdf(wo_ray, flip_z(wi, flip)),
+                specular: false,
+            };
+        }
+        if sampler.get_1d() < self.multiple_probability(wo) {
+            let (_, wi) = sample_cosine_hemisphere(sampler);
+            return BSDFSample {
+                wi: flip_z(wi, flip),
+                pdf: self.pdf_local(wo, wi),
+                f: self.bsdf(wo_ray, flip_z(wi, flip)),
+                specular: false,
+            };
+        }
+        let wo_t = refract_in(wo, self.ior);
+        let sample = self.base.sample_f(-wo_t, sampler);
+        // Light the base sends sideways is trapped by total internal reflection.
+        let Some(wi) = (sample.wi.z > 0. && sample.pdf > 0.)
+            .then(|| refract_out(sample.wi, self.ior))
+            .flatten()
+        else {
+            return invalid;
+        };
+        if sample.specular {
+            // A delta lobe keeps its direction through both refractions, without the change
+            // in solid angle, and keeps returning at the same angle.
+            let weight = (sample.wi.z / sample.pdf) * sample.f;
+            let returned = fresnel_dielectric(wi.z, 1., self.ior)
+                * exp(-(2. / sample.wi.z) * self.absorption)
+                * weight;
+            let bounces = math::v(
+                1. / (1. - returned.x.min(0.999)),
+                1. / (1. - returned.y.min(0.999)),
+                1. / (1. - returned.z.min(0.999)),
+            );
+            return BSDFSample {
+                wi: flip_z(wi, flip),
+                pdf: 1. - p_coat,
+                f: (1. / wi.z) * self.through_coat(wo, wi) * (weight * bounces),
+                specular: true,
+            };
+        }
+        BSDFSample {
+            wi: flip_z(wi, flip),
+            pdf: self.pdf_local(wo, wi),
+            f: self.bsdf(wo_ray, flip_z(wi, flip)),
+            specular: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tracer::microfacet::RoughConductor;
+    use crate::path_tracer::primitives::Lambertian;
+    use crate::path_tracer::testing::{albedo, incoming, pdf_integral, samples};
+    use crate::path_tracer::texture::Textured;
+
+    const COUNT: usize = 20000;
+    const INTEGRAL_COUNT: usize = 2_000_000;
+
+    fn white() -> Arc<dyn BSDF> {
+        Arc::new(Lambertian {
+            reflectance: math::v(1., 1., 1.),
+        })
+    }
+
+    // Reflects everything at every angle.
+    fn mirror_like(roughness: f64) -> Arc<dyn BSDF> {
+        Arc::new(RoughConductor {
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            eta: math::v(1., 1., 1.),
+            k: math::v(1e3, 1e3, 1e3),
+        })
+    }
+
+    fn materials() -> Vec<Arc<dyn BSDF>> {
+        vec![
+            Arc::new(Mix {
+                a: white(),
+                b: mirror_like(0.3),
+                weight: 0.3,
+                mask: None,
+            }),
+            Arc::new(Layered::new(white(), 1.5, 0.3, math::O)),
+            Arc::new(Layered::new(
+                mirror_like(0.5),
+                1.5,
+                0.3,
+                math::v(0.1, 0.2, 0.3),
+            )),
+        ]
+    }
+
+    #[test]
+    fn pdfs_match_their_samples() {
+        for (i, material) in materials().iter().enumerate() {
+            for cos_theta in [1., 0.5, 0.1, -0.5] {
+                let wo = incoming(cos_theta);
+                let pdf = |wi: V3| material.pdf(wo, wi);
+                let samples = samples(material.as_ref(), wo, COUNT);
+                for sample in samples.iter().filter(|s| s.pdf > 0.) {
+                    assert!((sample.pdf - pdf(sample.wi)).abs() <= 1e-9 * sample.pdf);
+                }
+                let kept = samples.iter().filter(|s| s.pdf > 0.).count() as f64 / COUNT as f64;
+                let integral = pdf_integral(pdf, INTEGRAL_COUNT);
+                assert!(
+                    (integral - kept).abs() < 0.01,
+                    "{} {} {} {}",
+                    i,
+                    cos_theta,
+                    integral,
+                    kept
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn white_furnace() {
+        for roughness in [0.1, 0.5, 1.] {
+            let mix = Mix {
+                a: white(),
+                b: mirror_like(roughness),
+                weight: 0.5,
+                mask: None,
+            };
+            let layered = Layered::new(white(), 1.5, roughness, math::O);
+            for cos_theta in [1., 0.5, 0.1] {
+                let wo = incoming(cos_theta);
+                let mix = albedo(&mix, wo, COUNT);
+                assert!(mix.x <= 1.01, "{} {} {:?}", roughness, cos_theta, mix);
+                // The bounces inside the coat are only approximated.
+                let layered = albedo(&layered, wo, COUNT);
+                assert!(
+                    layered.x <= 1.03,
+                    "{} {} {:?}",
+                    roughness,
+                    cos_theta,
+                    layered
+                );
+                // Nothing absorbs, only the rough lobes lose what bounces between facets.
+                if roughness <= 0.1 {
+                    assert!(mix.x > 0.95, "{} {} {:?}", roughness, cos_theta, mix);
+                    assert!(
+                        layered.x > 0.95,
+                        "{} {} {:?}",
+                        roughness,
+                        cos_theta,
+                        layered
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn textured_bases_share_the_coat_tables() {
+        let reflectance = |hit: &Intersection| math::v(hit.uv.0, 0.5, 0.2);
+        let base = Textured::new(move |hit| Lambertian {
+            reflectance: reflectance(hit),
+        });
+        let layered = Layered::new(Arc::new(base), 1.5, 0.3, math::v(0.1, 0.1, 0.1));
+        for u in [0.9, 0.2, 0.6] {
+            let hit = Intersection {
+                x: math::O,
+                n: math::B3,
+                s: math::B1,
+                t: 1.,
+                uv: (u, 0.),
+                dpdu: math::B1,
+                dpdv: math::B2,
+            };
+            let resolved = layered.at(&hit).unwrap();
+            let base = Arc::new(Lambertian {
+                reflectance: reflectance(&hit),
+            });
+            let direct = Layered::new(base, 1.5, 0.3, math::v(0.1, 0.1, 0.1));
+            for (cos_o, cos_i) in [(1., 0.5), (0.3, 0.9), (0.7, 0.1)] {
+                let (wo, wi) = (incoming(cos_o), -incoming(cos_i));
+                let (f, expected) = (resolved.bsdf(wo, wi), direct.bsdf(wo, wi));
+                assert!(
+                    math::abs2(&(f - expected)) <= 1e-18,
+                    "{:?} {:?}",
+                    f,
+                    expected
+                );
+            }
+        }
+    }
+}
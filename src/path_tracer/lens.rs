@@ -0,0 +1,480 @@
+use crate::math;
+use crate::math::{v, Ray, M3, V3};
+use crate::path_tracer::camera::{look_at_frame, Camera, Shutter};
+use crate::path_tracer::sampler::Sampler;
+use rayon::prelude::*;
+use std::{error, fmt};
+
+// One interface of a lens prescription, in meters. A curvature radius of zero marks the
+// aperture stop. `eta` is the index of refraction behind the interface (towards the film),
+// 0 meaning air.
+#[derive(Clone, Copy, Debug)]
+pub struct LensElement {
+    pub curvature_radius: f64,
+    pub thickness: f64,
+    pub eta: f64,
+    pub aperture_radius: f64,
+}
+
+// Tronnier's F/2 double Gauss (US patent 2,673,491), scaled to 50mm. PBRT's dgauss.50mm.dat.
+pub const DOUBLE_GAUSS_50MM: &str = "\
+# radius thickness eta aperture
+29.475   3.76   1.67   25.2
+84.83    0.12   1      25.2
+19.275   4.025  1.67   23
+40.77    3.275  1.699  23
+12.75    5.705  1      18
+0        4.5    0      17.1
+-14.495  1.18   1.603  17
+40.77    6.065  1.658  20
+-20.385  0.19   1      20
+437.065  3.22   1.717  20
+-39.73   5      1      20
+";
+
+#[derive(Debug, Clone)]
+struct InvalidPrescription(usize, String);
+
+impl fmt::Display for InvalidPrescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid lens prescription line {}: {:?}", self.0, self.1)
+    }
+}
+
+impl error::Error for InvalidPrescription {}
+
+// Focusing traces a ray parallel to the axis through the lens from either side.
+#[derive(Debug, Clone)]
+struct BlockedAxis(&'static str);
+
+impl fmt::Display for BlockedAxis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a ray along the lens axis from the {} doesn't make it through the lens",
+            self.0
+        )
+    }
+}
+
+impl error::Error for BlockedAxis {}
+
+// Parses a PBRT style prescription: one interface per line from the front of the lens to the
+// back, with curvature radius, thickness, index of refraction and aperture diameter in
+// millimeters. Lines starting with '#' are comments.
+pub fn parse_lens_prescription(src: &str) -> Result<Vec<LensElement>, Box<dyn error::Error>> {
+    let mut elements = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Box::new(InvalidPrescription(i + 1, line.to_string()));
+        let values = line
+            .split_whitespace()
+            .map(|s| s.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        if values.len() != 4 {
+            return Err(invalid());
+        }
+        elements.push(LensElement {
+            curvature_radius: 0.001 * values[0],
+            thickness: 0.001 * values[1],
+            eta: values[2],
+            aperture_radius: 0.001 * 0.5 * values[3],
+        });
+    }
+    if elements.is_empty() {
+        return Err(Box::new(InvalidPrescription(
+            0,
+            "no lens elements".to_string(),
+        )));
+    }
+    Ok(elements)
+}
+
+pub fn read_lens_file(path: &str) -> Result<Vec<LensElement>, Box<dyn error::Error>> {
+    parse_lens_prescription(&std::fs::read_to_string(path)?)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PupilBounds {
+    min: (f64, f64),
+    max: (f64, f64),
+}
+
+impl PupilBounds {
+    fn empty() -> Self {
+        Self {
+            min: (f64::INFINITY, f64::INFINITY),
+            max: (f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    fn square(half_size: f64) -> Self {
+        Self {
+            min: (-half_size, -half_size),
+            max: (half_size, half_size),
+        }
+    }
+
+    fn add(&mut self, x: f64, y: f64) {
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+    }
+
+    fn is_degenerate(&self) -> bool {
+        self.min.0 >= self.max.0 || self.min.1 >= self.max.1
+    }
+
+    fn expand(&self, delta: f64) -> Self {
+        Self {
+            min: (self.min.0 - delta, self.min.1 - delta),
+            max: (self.max.0 + delta, self.max.1 + delta),
+        }
+    }
+
+    fn area(&self) -> f64 {
+        (self.max.0 - self.min.0) * (self.max.1 - self.min.1)
+    }
+
+    fn lerp(&self, u: f64, v: f64) -> (f64, f64) {
+        (
+            self.min.0 + u * (self.max.0 - self.min.0),
+            self.min.1 + v * (self.max.1 - self.min.1),
+        )
+    }
+}
+
+// Film radius segments with their own exit pupil bounds.
+const EXIT_PUPIL_SEGMENTS: usize = 64;
+// Film positions and rear element grid resolution used to find each segment's bounds.
+const EXIT_PUPIL_FILM_SAMPLES: usize = 8;
+const EXIT_PUPIL_GRID: usize = 48;
+
+// Traces rays through a stack of spherical lens elements, after PBRT's realistic camera.
+// Camera space has the film at z = 0 and the lens in front of it along +z; the frame's
+// columns (right, up, forward) place it in the world with the film centered on `origin`.
+#[derive(Clone, Debug)]
+pub struct RealisticCamera {
+    pub origin: V3,
+    pub frame: M3,
+    pub elements: Vec<LensElement>,
+    pub film_half_width: f64,
+    pub film_half_height: f64,
+    pub shutter: Shutter,
+    exit_pupils: Vec<PupilBounds>,
+}
+
+impl RealisticCamera {
+    // `film_diagonal` is in meters. `aperture_diameter` stops the lens down, it can't open
+    // the stop wider than the prescription.
+    #[allow(clippy::too_many_arguments)]
+    pub fn look_at(
+        eye: V3,
+        target: V3,
+        up: V3,
+        mut elements: Vec<LensElement>,
+        film_diagonal: f64,
+        aspect_ratio: f64,
+        focus_distance: f64,
+        aperture_diameter: Option<f64>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        if let Some(diameter) = aperture_diameter {
+            for element in elements.iter_mut() {
+                if element.curvature_radius == 0. {
+                    element.aperture_radius = element.aperture_radius.min(0.5 * diameter);
+                }
+            }
+        }
+        let film_half_height = 0.5 * film_diagonal / (1. + aspect_ratio * aspect_ratio).sqrt();
+        let mut camera = Self {
+            origin: eye,
+            frame: look_at_frame(eye, target, up),
+            elements,
+            film_half_width: film_half_height * aspect_ratio,
+            film_half_height,
+            shutter: Shutter::instant(0.),
+            exit_pupils: Vec::new(),
+        };
+        let rear_thickness = camera.focus_thick_lens(focus_distance)?;
+        camera.elements.last_mut().unwrap().thickness = rear_thickness;
+        let film_radius = camera.film_radius();
+        camera.exit_pupils = (0..EXIT_PUPIL_SEGMENTS)
+            .into_par_iter()
+            .map(|i| {
+                let r0 = i as f64 / EXIT_PUPIL_SEGMENTS as f64 * film_radius;
+                let r1 = (i + 1) as f64 / EXIT_PUPIL_SEGMENTS as f64 * film_radius;
+                camera.bound_exit_pupil(r0, r1)
+            })
+            .collect();
+        Ok(camera)
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    fn film_radius(&self) -> f64 {
+        (self.film_half_width * self.film_half_width
+            + self.film_half_height * self.film_half_height)
+            .sqrt()
+    }
+
+    fn lens_front_z(&self) -> f64 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn lens_rear_z(&self) -> f64 {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn rear_element_radius(&self) -> f64 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    // Interfaces are visited back to front. Rays are flipped into lens space (film at the
+    // origin, lens along -z) while tracing.
+    fn trace_from_film(&self, r: &Ray) -> Option<Ray> {
+        let mut ray = flip_z(r);
+        let mut element_z = 0.;
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            element_z -= element.thickness;
+            let (t, n) = intersect_element(element, element_z, &ray)?;
+            let hit = ray.x + t * ray.d;
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            ray.x = hit;
+            if let Some(n) = n {
+                let eta_i = element.eta;
+                let eta_t = if i > 0 && self.elements[i - 1].eta != 0. {
+                    self.elements[i - 1].eta
+                } else {
+                    1.
+                };
+                ray.d = refract(-ray.d, n, eta_i / eta_t)?;
+            }
+        }
+        Some(flip_z(&ray))
+    }
+
+    fn trace_from_scene(&self, r: &Ray) -> Option<Ray> {
+        let mut ray = flip_z(r);
+        let mut element_z = -self.lens_front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let (t, n) = intersect_element(element, element_z, &ray)?;
+            let hit = ray.x + t * ray.d;
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            ray.x = hit;
+            if let Some(n) = n {
+                let eta_i = if i == 0 || self.elements[i - 1].eta == 0. {
+                    1.
+                } else {
+                    self.elements[i - 1].eta
+                };
+                let eta_t = if element.eta != 0. { element.eta } else { 1. };
+                ray.d = refract(-ray.d, n, eta_i / eta_t)?;
+            }
+            element_z += element.thickness;
+        }
+        Some(flip_z(&ray))
+    }
+
+    // Principal plane and focal point along the axis, both in lens space, from a ray
+    // parallel to the axis entering one side of the lens.
+    fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f64, f64) {
+        let tf = -r_out.x.x / r_out.d.x;
+        let fz = -(r_out.x.z + tf * r_out.d.z);
+        let tp = (r_in.x.x - r_out.x.x) / r_out.d.x;
+        let pz = -(r_out.x.z + tp * r_out.d.z);
+        (pz, fz)
+    }
+
+    fn thick_lens_approximation(&self) -> Result<([f64; 2], [f64; 2]), BlockedAxis> {
+        let x = 0.001 * 2. * self.film_radius();
+        let scene_ray = Ray {
+            x: v(x, 0., self.lens_front_z() + 1.),
+            d: v(0., 0., -1.),
+            time: 0.,
+        };
+        let film_ray = self
+            .trace_from_scene(&scene_ray)
+            .ok_or(BlockedAxis("scene"))?;
+        let (pz0, fz0) = Self::cardinal_points(&scene_ray, &film_ray);
+        let film_ray = Ray {
+            x: v(x, 0., self.lens_rear_z() - 1.),
+            d: v(0., 0., 1.),
+            time: 0.,
+        };
+        let scene_ray = self.trace_from_film(&film_ray).ok_or(BlockedAxis("film"))?;
+        let (pz1, fz1) = Self::cardinal_points(&film_ray, &scene_ray);
+        Ok(([pz0, pz1], [fz0, fz1]))
+    }
+
+    // Distance from the rear element to the film that focuses at `focus_distance` from the
+    // film, using the thick lens equation.
+    fn focus_thick_lens(&self, focus_distance: f64) -> Result<f64, BlockedAxis> {
+        let (pz, fz) = self.thick_lens_approximation()?;
+        let f = fz[0] - pz[0];
+        let z = -focus_distance;
+        let c = (pz[1] - z - pz[0]) * (pz[1] - z - 4. * f - pz[0]);
+        let delta = 0.5 * (pz[1] - z + pz[0] - c.max(0.).sqrt());
+        Ok(self.lens_rear_z() + delta)
+    }
+
+    // Bounds, on the plane of the rear element, of the points that rays from film points at
+    // radius r0..r1 on the +x axis pass through and make it out of the lens.
+    fn bound_exit_pupil(&self, r0: f64, r1: f64) -> PupilBounds {
+        let rear_bounds = PupilBounds::square(1.5 * self.rear_element_radius());
+        let rear_z = self.lens_rear_z();
+        let mut bounds = PupilBounds::empty();
+        for i in 0..EXIT_PUPIL_FILM_SAMPLES {
+            let film_x = r0 + (i as f64 + 0.5) / EXIT_PUPIL_FILM_SAMPLES as f64 * (r1 - r0);
+            let film_point = v(film_x, 0., 0.);
+            for gy in 0..EXIT_PUPIL_GRID {
+                for gx in 0..EXIT_PUPIL_GRID {
+                    let (x, y) = rear_bounds.lerp(
+                        (gx as f64 + 0.5) / EXIT_PUPIL_GRID as f64,
+                        (gy as f64 + 0.5) / EXIT_PUPIL_GRID as f64,
+                    );
+                    let ray = Ray {
+                        x: film_point,
+                        d: v(x, y, rear_z) - film_point,
+                        time: 0.,
+                    };
+                    if self.trace_from_film(&ray).is_some() {
+                        bounds.add(x, y);
+                    }
+                }
+            }
+        }
+        if bounds.is_degenerate() {
+            return rear_bounds;
+        }
+        bounds.expand(3. * self.rear_element_radius() / EXIT_PUPIL_GRID as f64)
+    }
+
+    // A point on the rear element plane for the film point, and the area of the bounds it was
+    // drawn from. Bounds were computed along +x, so they're rotated to the film point.
+    fn sample_exit_pupil(&self, film_x: f64, film_y: f64, u: (f64, f64)) -> (V3, f64) {
+        let r = (film_x * film_x + film_y * film_y).sqrt();
+        let index = ((r / self.film_radius() * EXIT_PUPIL_SEGMENTS as f64) as usize)
+            .min(EXIT_PUPIL_SEGMENTS - 1);
+        let bounds = &self.exit_pupils[index];
+        let (x, y) = bounds.lerp(u.0, u.1);
+        let (sin_theta, cos_theta) = if r == 0. {
+            (0., 1.)
+        } else {
+            (film_y / r, film_x / r)
+        };
+        (
+            v(
+                cos_theta * x - sin_theta * y,
+                sin_theta * x + cos_theta * y,
+                self.lens_rear_z(),
+            ),
+            bounds.area(),
+        )
+    }
+}
+
+// Also normalizes the direction, refraction expects unit vectors.
+fn flip_z(r: &Ray) -> Ray {
+    Ray {
+        x: v(r.x.x, r.x.y, -r.x.z),
+        d: math::normalize(&v(r.d.x, r.d.y, -r.d.z)),
+        time: r.time,
+    }
+}
+
+// Distance along the ray to the interface at `element_z`, with the surface normal facing the
+// ray for refracting interfaces and `None` for the aperture stop.
+fn intersect_element(element: &LensElement, element_z: f64, r: &Ray) -> Option<(f64, Option<V3>)> {
+    if element.curvature_radius == 0. {
+        let t = (element_z - r.x.z) / r.d.z;
+        return if t.is_finite() && t >= 0. {
+            Some((t, None))
+        } else {
+            None
+        };
+    }
+    let radius = element.curvature_radius;
+    let o = r.x - v(0., 0., element_z + radius);
+    let a = math::dot(&r.d, &r.d);
+    let b = 2. * math::dot(&r.d, &o);
+    let c = math::dot(&o, &o) - radius * radius;
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let q = if b < 0. {
+        -0.5 * (b - root)
+    } else {
+        -0.5 * (b + root)
+    };
+    let (t0, t1) = (q / a, c / q);
+    let (t0, t1) = (t0.min(t1), t0.max(t1));
+    // Which of the two sphere hits is the lens surface depends on the direction of travel
+    // and which way the surface bulges.
+    let t = if (r.d.z > 0.) ^ (radius < 0.) { t0 } else { t1 };
+    if t.is_nan() || t < 0. {
+        return None;
+    }
+    let mut n = math::normalize(&(o + t * r.d));
+    if math::dot(&n, &r.d) > 0. {
+        n = -n;
+    }
+    Some((t, Some(n)))
+}
+
+// Refraction of the direction `wi` (pointing away from the surface) through the normal `n`
+// on the same side, `eta` being eta_i / eta_t. `None` on total internal reflection.
+fn refract(wi: V3, n: V3, eta: f64) -> Option<V3> {
+    let cos_theta_i = math::dot(&n, &wi);
+    let sin2_theta_i = (1. - cos_theta_i * cos_theta_i).max(0.);
+    let sin2_theta_t = eta * eta * sin2_theta_i;
+    if sin2_theta_t >= 1. {
+        return None;
+    }
+    let cos_theta_t = (1. - sin2_theta_t).sqrt();
+    Some(eta * -wi + (eta * cos_theta_i - cos_theta_t) * n)
+}
+
+impl Camera for RealisticCamera {
+    fn sample_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        self.sample_weighted_ray(x, y, sampler).map(|(_, ray)| ray)
+    }
+
+    // The weight is cos^4 of the angle to the film normal, scaled by the area of the exit
+    // pupil bounds relative to the one at the film center.
+    fn sample_weighted_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Option<(f64, Ray)> {
+        // The image on the film is upside down and mirrored.
+        let film_point = v(-x * self.film_half_width, -y * self.film_half_height, 0.);
+        let (rear_point, bounds_area) =
+            self.sample_exit_pupil(film_point.x, film_point.y, sampler.get_2d());
+        let time = self.shutter.sample_time(sampler);
+        let film_ray = Ray {
+            x: film_point,
+            d: rear_point - film_point,
+            time,
+        };
+        let out = self.trace_from_film(&film_ray)?;
+        let cos_theta = math::normalize(&film_ray.d).z;
+        let weight = cos_theta.powi(4) * bounds_area / self.exit_pupils[0].area();
+        Some((
+            weight,
+            Ray {
+                x: self.origin + self.frame * out.x,
+                d: math::normalize(&(self.frame * out.d)),
+                time,
+            },
+        ))
+    }
+}
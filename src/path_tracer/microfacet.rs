@@ -0,0 +1,647 @@
+use crate::math;
+use crate::math::V3;
+use crate::path_tracer::primitives::Dielectric;
+use crate::path_tracer::sampler::Sampler;
+use crate::path_tracer::{
+    flip_z, fresnel_dielectric, outgoing, radiance_scale, reflect, refract, BSDFSample, BSDF,
+};
+use std::f64::consts::PI;
+use std::ops;
+
+// Distribution of microfacet normals in the local shading frame (normal along z), with the
+// matching Smith masking-shadowing. Directions point away from the surface.
+pub trait MicrofacetDistribution: Send + Sync {
+    fn d(&self, wm: V3) -> f64;
+    fn lambda(&self, w: V3) -> f64;
+    // Samples a normal seen from `w`, distributed according to `pdf`.
+    fn sample_wm(&self, w: V3, u: (f64, f64)) -> V3;
+    // Below this roughness surfaces are rendered as perfectly smooth.
+    fn effectively_smooth(&self) -> bool;
+
+    fn pdf(&self, w: V3, wm: V3) -> f64 {
+        self.visible_d(w, wm)
+    }
+
+    fn g1(&self, w: V3) -> f64 {
+        1. / (1. + self.lambda(w))
+    }
+
+    fn g(&self, wo: V3, wi: V3) -> f64 {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+
+    fn visible_d(&self, w: V3, wm: V3) -> f64 {
+        self.g1(w) / w.z.abs() * self.d(wm) * math::dot(&w, &wm).abs()
+    }
+}
+
+// GGX, with separate roughness along the tangent and bitangent for anisotropic surfaces.
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitz {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl TrowbridgeReitz {
+    // Perceptual roughness in [0, 1], squared into alpha.
+    pub fn from_roughness(roughness: f64) -> Self {
+        let alpha = roughness * roughness;
+        Self {
+            alpha_x: alpha,
+            alpha_y: alpha,
+        }
+    }
+}
+
+impl MicrofacetDistribution for TrowbridgeReitz {
+    fn d(&self, wm: V3) -> f64 {
+        if wm.z <= 0. {
+            return 0.;
+        }
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let denominator = x * x + y * y + wm.z * wm.z;
+        1. / (PI * self.alpha_x * self.alpha_y * denominator * denominator)
+    }
+
+    fn lambda(&self, w: V3) -> f64 {
+        if w.z == 0. {
+            return f64::INFINITY;
+        }
+        let x = self.alpha_x * w.x;
+        let y = self.alpha_y * w.y;
+        let alpha2_tan2_theta = (x * x + y * y) / (w.z * w.z);
+        0.5 * ((1. + alpha2_tan2_theta).sqrt() - 1.)
+    }
+
+    // Heitz 2018, "Sampling the GGX Distribution of Visible Normals".
+    fn sample_wm(&self, w: V3, u: (f64, f64)) -> V3 {
+        let mut wh = math::normalize(&math::v(self.alpha_x * w.x, self.alpha_y * w.y, w.z));
+        if wh.z < 0. {
+            wh = -wh;
+        }
+        let t1 = if wh.z < 0.99999 {
+            math::normalize(&math::cross(&math::B3, &wh))
+        } else {
+            math::B1
+        };
+        let t2 = math::cross(&wh, &t1);
+        let r = u.0.sqrt();
+        let phi = 2. * PI * u.1;
+        let (px, py) = (r * phi.cos(), r * phi.sin());
+        let h = (1. - px * px).sqrt();
+        let s = 0.5 * (1. + wh.z);
+        let py = (1. - s) * h + s * py;
+        let pz = (1. - px * px - py * py).max(0.).sqrt();
+        let nh = px * t1 + py * t2 + pz * wh;
+        math::normalize(&math::v(
+            self.alpha_x * nh.x,
+            self.alpha_y * nh.y,
+            nh.z.max(1e-6),
+        ))
+    }
+
+    fn effectively_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+}
+
+// Gaussian distribution of slopes, with a longer tail of steep facets than GGX.
+#[derive(Clone, Copy, Debug)]
+pub struct Beckmann {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl Beckmann {
+    pub fn from_roughness(roughness: f64) -> Self {
+        let alpha = roughness * roughness;
+        Self {
+            alpha_x: alpha,
+            alpha_y: alpha,
+        }
+    }
+}
+
+impl MicrofacetDistribution for Beckmann {
+    fn d(&self, wm: V3) -> f64 {
+        if wm.z <= 0. {
+            return 0.;
+        }
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let cos2_theta = wm.z * wm.z;
+        (-(x * x + y * y) / cos2_theta).exp()
+            / (PI * self.alpha_x * self.alpha_y * cos2_theta * cos2_theta)
+    }
+
+    // Walter et al.'s rational approximation.
+    fn lambda(&self, w: V3) -> f64 {
+        if w.z == 0. {
+            return f64::INFINITY;
+        }
+        let x = self.alpha_x * w.x;
+        let y = self.alpha_y * w.y;
+        let a = w.z.abs() / (x * x + y * y).sqrt();
+        if a >= 1.6 {
+            return 0.;
+        }
+        (1. - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a)
+    }
+
+    // Samples all normals by projected area rather than just the visible ones: slopes are
+    // Gaussian, so they are drawn directly.
+    fn sample_wm(&self, _w: V3, u: (f64, f64)) -> V3 {
+        let r = (-(1. - u.0).ln()).sqrt();
+        let phi = 2. * PI * u.1;
+        math::normalize(&math::v(
+            -self.alpha_x * r * phi.cos(),
+            -self.alpha_y * r * phi.sin(),
+            1.,
+        ))
+    }
+
+    fn effectively_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    fn pdf(&self, _w: V3, wm: V3) -> f64 {
+        self.d(wm) * wm.z.abs()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn real(re: f64) -> Self {
+        Self { re, im: 0. }
+    }
+
+    fn norm(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    fn sqrt(&self) -> Self {
+        let n = self.norm().sqrt();
+        let t1 = (0.5 * (n + self.re.abs())).sqrt();
+        let t2 = 0.5 * self.im / t1;
+        if n == 0. {
+            Self::real(0.)
+        } else if self.re >= 0. {
+            Self { re: t1, im: t2 }
+        } else {
+            Self {
+                re: t2.abs(),
+                im: t1.copysign(self.im),
+            }
+        }
+    }
+}
+
+impl ops::Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im,
+        }
+    }
+}
+
+impl ops::Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im,
+        }
+    }
+}
+
+impl ops::Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+}
+
+impl ops::Div for Complex {
+    type Output = Complex;
+    fn div(self, rhs: Complex) -> Complex {
+        let scale = 1. / rhs.norm();
+        Complex {
+            re: scale * (self.re * rhs.re + self.im * rhs.im),
+            im: scale * (self.im * rhs.re - self.re * rhs.im),
+        }
+    }
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction eta + i k.
+fn fresnel_complex_1d(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let eta = Complex { re: eta, im: k };
+    let cos_i_c = Complex::real(cos_i);
+    let sin2_i = Complex::real(1. - cos_i * cos_i);
+    let sin2_t = sin2_i / (eta * eta);
+    let cos_t = (Complex::real(1.) - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i_c - cos_t) / (eta * cos_i_c + cos_t);
+    let r_perpendicular = (cos_i_c - eta * cos_t) / (cos_i_c + eta * cos_t);
+    0.5 * (r_parallel.norm() + r_perpendicular.norm())
+}
+
+pub fn fresnel_complex(cos_i: f64, eta: V3, k: V3) -> V3 {
+    math::v(
+        fresnel_complex_1d(cos_i, eta.x, k.x),
+        fresnel_complex_1d(cos_i, eta.y, k.y),
+        fresnel_complex_1d(cos_i, eta.z, k.z),
+    )
+}
+
+// Metal with a complex index of refraction per RGB channel.
+#[derive(Clone, Copy, Debug)]
+pub struct RoughConductor<D: MicrofacetDistribution> {
+    pub distribution: D,
+    pub eta: V3,
+    pub k: V3,
+}
+
+impl RoughConductor<TrowbridgeReitz> {
+    pub fn gold(roughness: f64) -> Self {
+        Self {
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            eta: math::v(0.143, 0.374, 1.442),
+            k: math::v(3.983, 2.385, 1.603),
+        }
+    }
+
+    pub fn copper(roughness: f64) -> Self {
+        Self {
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            eta: math::v(0.200, 0.924, 1.102),
+            k: math::v(3.912, 2.452, 2.142),
+        }
+    }
+
+    pub fn aluminium(roughness: f64) -> Self {
+        Self {
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            eta: math::v(1.657, 0.880, 0.521),
+            k: math::v(9.224, 6.270, 4.837),
+        }
+    }
+}
+
+impl<D: MicrofacetDistribution> RoughConductor<D> {
+    // pdf of sampling `wi` from `wo`, both on the upper side and pointing away.
+    fn pdf_local(&self, wo: V3, wi: V3) -> f64 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return 0.;
+        }
+        let wm = math::normalize(&(wo + wi));
+        self.distribution.pdf(wo, wm) / (4. * math::dot(&wo, &wm).abs())
+    }
+
+    fn f_local(&self, wo: V3, wi: V3) -> V3 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return math::O;
+        }
+        let wm = wo + wi;
+        if math::abs2(&wm) == 0. {
+            return math::O;
+        }
+        let wm = math::normalize(&wm);
+        let fresnel = fresnel_complex(math::dot(&wo, &wm).abs(), self.eta, self.k);
+        (self.distribution.d(wm) * self.distribution.g(wo, wi) / (4. * wo.z * wi.z)) * fresnel
+    }
+}
+
+impl<D: MicrofacetDistribution> BSDF for RoughConductor<D> {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        if self.distribution.effectively_smooth() {
+            return math::O;
+        }
+        let (wo, flip) = outgoing(wo);
+        self.f_local(wo, flip_z(wi, flip))
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        if self.distribution.effectively_smooth() {
+            return 0.;
+        }
+        let (wo, flip) = outgoing(wo);
+        self.pdf_local(wo, flip_z(wi, flip))
+    }
+
+    fn is_specular(&self) -> bool {
+        self.distribution.effectively_smooth()
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (wo, flip) = outgoing(wo);
+        if self.distribution.effectively_smooth() {
+            let wi = math::v(-wo.x, -wo.y, wo.z);
+            return BSDFSample {
+                wi: flip_z(wi, flip),
+                pdf: 1.,
+                f: (1. / wi.z.abs()) * fresnel_complex(wi.z.abs(), self.eta, self.k),
+                specular: true,
+            };
+        }
+        let wm = self.distribution.sample_wm(wo, sampler.get_2d());
+        let wi = reflect(wo, wm);
+        BSDFSample {
+            wi: flip_z(wi, flip),
+            pdf: self.pdf_local(wo, wi),
+            f: self.f_local(wo, wi),
+            specular: false,
+        }
+    }
+}
+
+fn is_reflection(wo: V3, wi: V3) -> bool {
+    wo.z * wi.z > 0.
+}
+
+// Frosted glass after Walter et al. 2007, "Microfacet Models for Refraction through Rough
+// Surfaces". Like `Dielectric`, the normal points out of the object with `ior` inside.
+#[derive(Clone, Copy, Debug)]
+pub struct RoughDielectric<D: MicrofacetDistribution> {
+    pub distribution: D,
+    pub ior: f64,
+    pub reflectance: V3,
+    pub transmittance: V3,
+}
+
+impl<D: MicrofacetDistribution> RoughDielectric<D> {
+    pub fn new(ior: f64, distribution: D) -> Self {
+        Self {
+            distribution,
+            ior,
+            reflectance: math::v(1., 1., 1.),
+            transmittance: math::v(1., 1., 1.),
+        }
+    }
+
+    // Fresnel reflectance at a microfacet, `cos_o` being the cosine towards the viewer.
+    fn fresnel(&self, cos_o: f64) -> f64 {
+        if cos_o > 0. {
+            fresnel_dielectric(cos_o, 1., self.ior)
+        } else {
+            fresnel_dielectric(-cos_o, self.ior, 1.)
+        }
+    }
+
+    // Generalized half vector of `wo` and `wi` (both pointing away) facing up, together with
+    // eta_t / eta_i seen from `wo`, 1 for reflection.
+    fn half_vector(&self, wo: V3, wi: V3) -> Option<(V3, f64)> {
+        if wo.z == 0. || wi.z == 0. {
+            return None;
+        }
+        let eta = if is_reflection(wo, wi) {
+            1.
+        } else if wo.z > 0. {
+            self.ior
+        } else {
+            1. / self.ior
+        };
+        let wm = eta * wi + wo;
+        if math::abs2(&wm) == 0. {
+            return None;
+        }
+        let wm = math::normalize(&wm);
+        let wm = if wm.z < 0. { -wm } else { wm };
+        // Facets seen from behind don't contribute.
+        if math::dot(&wm, &wi) * wi.z < 0. || math::dot(&wm, &wo) * wo.z < 0. {
+            return None;
+        }
+        Some((wm, eta))
+    }
+
+    fn f_local(&self, wo: V3, wi: V3) -> V3 {
+        let Some((wm, eta)) = self.half_vector(wo, wi) else {
+            return math::O;
+        };
+        let fresnel = self.fresnel(math::dot(&wo, &wm));
+        let dg = self.distribution.d(wm) * self.distribution.g(wo, wi);
+        if is_reflection(wo, wi) {
+            return (dg * fresnel / (4. * wo.z * wi.z).abs()) * self.reflectance;
+        }
+        let (cos_im, cos_om) = (math::dot(&wi, &wm), math::dot(&wo, &wm));
+        let denominator = (cos_im + cos_om / eta).powi(2) * wi.z * wo.z;
+        let f = dg * (1. - fresnel) * (cos_im * cos_om / denominator).abs() * radiance_scale(eta);
+        f * self.transmittance
+    }
+
+    fn pdf_local(&self, wo: V3, wi: V3) -> f64 {
+        let Some((wm, eta)) = self.half_vector(wo, wi) else {
+            return 0.;
+        };
+        let cos_om = math::dot(&wo, &wm);
+        let fresnel = self.fresnel(cos_om);
+        let pdf_wm = self.distribution.pdf(wo, wm);
+        if is_reflection(wo, wi) {
+            return pdf_wm / (4. * cos_om.abs()) * fresnel;
+        }
+        let cos_im = math::dot(&wi, &wm);
+        let dwm_dwi = cos_im.abs() / (cos_im + cos_om / eta).powi(2);
+        pdf_wm * dwm_dwi * (1. - fresnel)
+    }
+
+    // Without a change in index, rough or not, light passes straight through.
+    fn is_smooth(&self) -> bool {
+        self.ior == 1. || self.distribution.effectively_smooth()
+    }
+
+    fn smooth(&self) -> Dielectric {
+        Dielectric {
+            ior: self.ior,
+            reflectance: self.reflectance,
+            transmittance: self.transmittance,
+        }
+    }
+}
+
+impl<D: MicrofacetDistribution> BSDF for RoughDielectric<D> {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        if self.is_smooth() {
+            return math::O;
+        }
+        self.f_local(-wo, wi)
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        if self.is_smooth() {
+            return 0.;
+        }
+        self.pdf_local(-wo, wi)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.is_smooth()
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        if self.is_smooth() {
+            return self.smooth().sample_f(wo, sampler);
+        }
+        let u = sampler.get_2d();
+        let choice = sampler.get_1d();
+        let d = wo;
+        let wo = -d;
+        let wm = self.distribution.sample_wm(wo, u);
+        let cos_om = math::dot(&wo, &wm);
+        let reflected = choice < self.fresnel(cos_om);
+        let wi = if reflected {
+            Some(reflect(wo, wm))
+        } else if cos_om > 0. {
+            refract(d, wm, 1. / self.ior)
+        } else {
+            refract(d, -wm, self.ior)
+        };
+        match wi {
+            // A direction that ends up on the other branch's side would be scored with the
+            // other branch's density, not the one it was drawn from.
+            Some(wi) if is_reflection(wo, wi) == reflected => BSDFSample {
+                wi,
+                pdf: self.pdf_local(wo, wi),
+                f: self.f_local(wo, wi),
+                specular: false,
+            },
+            _ => BSDFSample {
+                wi: math::B3,
+                pdf: 0.,
+                f: math::O,
+                specular: false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tracer::testing::{albedo, incoming, pdf_integral, samples};
+
+    const COUNT: usize = 20000;
+    // Uniformly spread directions rarely land in a glossy lobe, the pdf integral needs more.
+    const INTEGRAL_COUNT: usize = 4_000_000;
+
+    #[test]
+    fn rough_conductor_pdf_matches_its_samples() {
+        let conductor = RoughConductor::gold(0.5);
+        for cos_theta in [1., 0.5, 0.1] {
+            let wo = incoming(cos_theta);
+            let pdf = |wi: V3| conductor.pdf(wo, wi);
+            let samples = samples(&conductor, wo, COUNT);
+            for sample in samples.iter() {
+                assert!((sample.pdf - pdf(sample.wi)).abs() <= 1e-9 * sample.pdf);
+            }
+            // Samples reflected below the horizon are dropped, the pdf only covers the rest.
+            let kept = samples.iter().filter(|s| s.pdf > 0.).count() as f64 / COUNT as f64;
+            let integral = pdf_integral(pdf, INTEGRAL_COUNT);
+            assert!(
+                (integral - kept).abs() < 0.01,
+                "{} {} {}",
+                cos_theta,
+                integral,
+                kept
+            );
+        }
+    }
+
+    #[test]
+    fn rough_conductor_conserves_energy() {
+        for roughness in [0.1, 0.5, 1.] {
+            // Reflects everything at every angle.
+            let conductor = RoughConductor {
+                distribution: TrowbridgeReitz::from_roughness(roughness),
+                eta: math::v(1., 1., 1.),
+                k: math::v(1e3, 1e3, 1e3),
+            };
+            for cos_theta in [1., 0.5, 0.1] {
+                let albedo = albedo(&conductor, incoming(cos_theta), COUNT);
+                assert!(albedo.x <= 1.01, "{} {} {:?}", roughness, cos_theta, albedo);
+                // Single scattering only loses what bounces between microfacets.
+                if roughness <= 0.1 {
+                    assert!(albedo.x > 0.95, "{} {} {:?}", roughness, cos_theta, albedo);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rough_dielectric_pdf_matches_its_samples() {
+        let glass = RoughDielectric::new(1.5, Beckmann::from_roughness(0.5));
+        // From outside, then from inside the glass.
+        for cos_theta in [1., 0.5, 0.1, -0.5] {
+            let wo = incoming(cos_theta);
+            let pdf = |wi: V3| glass.pdf(wo, wi);
+            let samples = samples(&glass, wo, COUNT);
+            // Failed samples come back with a zero pdf.
+            for sample in samples.iter().filter(|s| s.pdf > 0.) {
+                assert!((sample.pdf - pdf(sample.wi)).abs() <= 1e-9 * sample.pdf);
+            }
+            let kept = samples.iter().filter(|s| s.pdf > 0.).count() as f64 / COUNT as f64;
+            let integral = pdf_integral(pdf, INTEGRAL_COUNT);
+            assert!(
+                (integral - kept).abs() < 0.01,
+                "{} {} {}",
+                cos_theta,
+                integral,
+                kept
+            );
+        }
+    }
+
+    #[test]
+    fn rough_dielectric_conserves_energy() {
+        for roughness in [0.1, 0.5, 1.] {
+            let glass = RoughDielectric::new(1.5, TrowbridgeReitz::from_roughness(roughness));
+            for cos_theta in [1., 0.5, 0.1] {
+                // Light leaving the glass spreads out, so at most everything comes out.
+                let leaving = albedo(&glass, incoming(cos_theta), COUNT);
+                assert!(
+                    leaving.x <= 1.01,
+                    "{} {} {:?}",
+                    roughness,
+                    cos_theta,
+                    leaving
+                );
+                // Light entering it gets concentrated by up to ior².
+                let entering = albedo(&glass, incoming(-cos_theta), COUNT);
+                assert!(
+                    entering.x <= 1.01 * 1.5 * 1.5,
+                    "{} {} {:?}",
+                    roughness,
+                    cos_theta,
+                    entering
+                );
+            }
+        }
+    }
+}
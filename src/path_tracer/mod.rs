@@ -1,13 +1,25 @@
-use crate::math::{Intersection, Ray, M3};
+use crate::math::{Intersection, Ray};
 use crate::{math, V3};
-use rand::distributions::Standard;
-use rand::{thread_rng, Rng};
-use rand_distr::StandardNormal;
+use sampler::Sampler;
 use std::f32::consts::PI;
 use std::sync::Arc;
+pub mod animation;
+pub mod aperture;
 pub mod bvh;
+pub mod camera;
+pub mod film;
+pub mod layered;
+pub mod lens;
+pub mod microfacet;
 pub mod obj;
 pub mod primitives;
+pub mod principled;
+pub mod render;
+pub mod rng;
+pub mod sampler;
+#[cfg(test)]
+mod testing;
+pub mod texture;
 
 pub struct RenderContext {
     pub imp: bool,
@@ -17,10 +29,84 @@ pub struct RenderContext {
     pub preview: bool,
 }
 
-pub trait BSDF {
-    fn sample_wi(&self, wo: V3) -> (f64, V3);
+pub struct BSDFSample {
+    pub wi: V3,
+    pub pdf: f64,
+    pub f: V3,
+    pub specular: bool,
+}
+
+pub trait BSDF: Send + Sync {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3);
     fn bsdf(&self, wo: math::V3, wi: math::V3) -> math::V3;
     fn radiance(&self, wo: math::V3) -> math::V3;
+
+    // Solid angle density with which `sample_wi` and `sample_f` pick `wi`, so other
+    // sampling strategies can be weighted against them. Zero for delta distributions.
+    fn pdf(&self, wo: V3, wi: V3) -> f64;
+
+    // Delta distributions (perfect mirrors, smooth glass) are zero for every pair of
+    // directions `bsdf` could be asked about, they can only be sampled. The integrator skips
+    // light sampling for them.
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // Materials that vary over the surface return the BSDF at one hit, `hit` being in the
+    // space of the solid it belongs to.
+    fn at(&self, _hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        None
+    }
+
+    // Normal and bump maps tilt the shading normal and tangent of a hit before the shading
+    // frame is built from them.
+    fn perturb(&self, _hit: &mut Intersection) {}
+
+    // Sampled direction with its pdf and BSDF value. For a specular sample the pdf is 1 and
+    // `f` already includes the 1 / |cos| of the delta distribution.
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (pdf, wi) = self.sample_wi(wo, sampler);
+        BSDFSample {
+            wi,
+            pdf,
+            f: self.bsdf(wo, wi),
+            specular: false,
+        }
+    }
+}
+
+impl<B: BSDF + ?Sized> BSDF for Arc<B> {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        (**self).sample_wi(wo, sampler)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        (**self).bsdf(wo, wi)
+    }
+
+    fn radiance(&self, wo: V3) -> V3 {
+        (**self).radiance(wo)
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        (**self).pdf(wo, wi)
+    }
+
+    fn is_specular(&self) -> bool {
+        (**self).is_specular()
+    }
+
+    fn at(&self, hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        (**self).at(hit)
+    }
+
+    fn perturb(&self, hit: &mut Intersection) {
+        (**self).perturb(hit)
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        (**self).sample_f(wo, sampler)
+    }
 }
 
 pub struct Scene {
@@ -28,138 +114,141 @@ pub struct Scene {
     pub light: Box<dyn Light>,
 }
 
-fn sample_hemisphere() -> (f64, V3) {
-    let x = thread_rng().sample(StandardNormal);
-    let y = thread_rng().sample(StandardNormal);
-    let z: f64 = thread_rng().sample(StandardNormal);
-    let z_pos = if z > 0.0 { z } else { -z };
-    (
-        (1. / (2. * PI)) as f64,
-        math::normalize(&math::v(x, y, z_pos)),
-    )
-}
-
-fn sample_disk() -> V3 {
-    loop {
-        let x: f64 = thread_rng().sample(Standard);
-        let y: f64 = thread_rng().sample(Standard);
-        let v = math::v(x, y, 0.);
-        if math::abs2(&v) <= 1. {
-            return v;
-        }
+// Shirley-Chiu concentric mapping of the unit square onto the unit disk.
+fn sample_disk(sampler: &mut dyn Sampler) -> V3 {
+    let (u, v) = sampler.get_2d();
+    let (x, y) = (2. * u - 1., 2. * v - 1.);
+    if x == 0. && y == 0. {
+        return math::O;
     }
+    let quarter_pi = PI as f64 / 4.;
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, quarter_pi * (y / x))
+    } else {
+        (y, 2. * quarter_pi - quarter_pi * (x / y))
+    };
+    math::v(r * theta.cos(), r * theta.sin(), 0.)
 }
 
-fn sample_sphere() -> V3 {
-    let x = thread_rng().sample(StandardNormal);
-    let y = thread_rng().sample(StandardNormal);
-    let z: f64 = thread_rng().sample(StandardNormal);
-    math::normalize(&math::v(x, y, z))
+// Malley's method: points on the disk projected up onto the hemisphere are distributed
+// with density cos(theta) / pi.
+fn sample_cosine_hemisphere(sampler: &mut dyn Sampler) -> (f64, V3) {
+    let p = sample_disk(sampler);
+    let z = (1. - p.x * p.x - p.y * p.y).max(0.).sqrt();
+    (z / PI as f64, math::v(p.x, p.y, z))
 }
 
-type IntersectionWithBSDF = (Intersection, Arc<dyn BSDF>);
-pub trait Object {
-    fn intersect(&self, r: &Ray) -> Option<IntersectionWithBSDF>;
+fn sample_sphere(sampler: &mut dyn Sampler) -> V3 {
+    let (u, v) = sampler.get_2d();
+    let z = 1. - 2. * u;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI as f64 * v;
+    math::v(r * phi.cos(), r * phi.sin(), z)
 }
 
-pub struct Photon {
-    pub d: Ray,
-    pub radiance: V3,
+// Unpolarized Fresnel reflectance at a boundary between dielectrics, `cos_i` being the cosine
+// on the incident side with index `eta_i`. Total internal reflection gives 1.
+fn fresnel_dielectric(cos_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let sin_t = eta_i / eta_t * (1. - cos_i * cos_i).max(0.).sqrt();
+    if sin_t >= 1. {
+        return 1.;
+    }
+    let cos_t = (1. - sin_t * sin_t).max(0.).sqrt();
+    let r_parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let r_perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
 }
 
-#[derive(Clone, Debug)]
-pub struct Camera {
-    pub lens_origin: V3,
-    pub sensor_origin: V3,
-    pub sensor_x: V3,
-    pub sensor_y: V3,
-    pub lens_direction: V3,
-    pub lens_radius: f64,
-    pub focal_length: f64,
+// Factor on radiance crossing a refractive boundary, `eta` being the index of the side it
+// comes from over the index of the side it enters. Radiance gets compressed into the smaller
+// solid angle of the denser side.
+fn radiance_scale(eta: f64) -> f64 {
+    1. / (eta * eta)
 }
 
-impl Camera {
-    pub fn new(
-        lens_origin: V3,
-        lens_direction: V3,
-        lens_radius: f64,
-        focal_length: f64,
-        focus_distance: f64,
-        sensor_x: V3,
-        sensor_y: V3,
-    ) -> Self {
-        Self {
-            lens_origin,
-            sensor_origin: lens_origin
-                - (1.0 / (1. / focal_length - 1. / focus_distance)) * lens_direction,
-            sensor_x,
-            sensor_y,
-            lens_direction,
-            lens_radius,
-            focal_length,
-        }
-    }
-    fn get_lens_basis(&self) -> M3 {
-        let basis = if math::dot(&math::B1, &self.lens_direction).abs() < 0.9 {
-            math::B1
-        } else {
-            math::B2
-        };
-        let camera_b1 = math::normalize(
-            &(basis - math::dot(&basis, &self.lens_direction) * self.lens_direction),
-        );
-        let camera_b2 = math::cross(&camera_b1, &self.lens_direction);
-        M3::new(camera_b1, camera_b2, self.lens_direction)
-    }
-    fn sample_lens_point(&self) -> V3 {
-        let disk_point = sample_disk();
-        self.lens_radius * (self.get_lens_basis() * disk_point) + self.lens_origin
+// Refracts the incoming direction `d` through a surface with normal `n` facing against it,
+// `eta` being eta_i / eta_t. `None` on total internal reflection.
+fn refract(d: V3, n: V3, eta: f64) -> Option<V3> {
+    let cos_i = -math::dot(&n, &d);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i).max(0.);
+    if sin2_t >= 1. {
+        return None;
     }
+    let cos_t = (1. - sin2_t).sqrt();
+    Some(eta * d + (eta * cos_i - cos_t) * n)
+}
 
-    fn get_lens_ray(&self, sensor_point: V3, lens_point: V3) -> Ray {
-        let l2w = self.get_lens_basis();
-        let w2l = l2w.t();
-        let normalized_sensor_point = sensor_point - self.lens_origin;
-        let normalized_lens_point = lens_point - self.lens_origin;
-        let sensor_point_lens_space = w2l * normalized_sensor_point;
-        let lens_point_lens_space = w2l * normalized_lens_point;
-        let sensor_lens_dir = lens_point_lens_space - sensor_point_lens_space;
-        let focal_lens_dir = self.focal_length / sensor_lens_dir.z * sensor_lens_dir;
-        let focal_plane_point = sensor_point_lens_space - focal_lens_dir;
-        let ray_dir = l2w * math::normalize(&(-focal_plane_point));
-        Ray {
-            x: lens_point,
-            d: ray_dir,
-        }
+// The integrator hands BSDFs the direction the ray travels in. Microfacet models want it
+// pointing away from the surface, on the upper side; `flip` mirrors back faces over.
+fn outgoing(wo: V3) -> (V3, f64) {
+    let w = -wo;
+    if w.z < 0. {
+        (math::v(w.x, w.y, -w.z), -1.)
+    } else {
+        (w, 1.)
     }
+}
 
-    fn get_sensor_point(&self, x: f64, y: f64) -> V3 {
-        self.sensor_origin + x * self.sensor_x + y * self.sensor_y
-    }
+fn flip_z(w: V3, flip: f64) -> V3 {
+    math::v(w.x, w.y, flip * w.z)
+}
 
-    pub fn sample_ray(&self, x: f64, y: f64) -> Ray {
-        self.get_lens_ray(self.get_sensor_point(x, y), self.sample_lens_point())
-    }
+fn reflect(w: V3, n: V3) -> V3 {
+    2. * math::dot(&w, &n) * n - w
 }
 
-pub trait Light {
-    fn sample_rad(&self, p: V3) -> (f64, Photon);
+type IntersectionWithBSDF = (Intersection, Arc<dyn BSDF>);
+pub trait Object: Send + Sync {
+    fn intersect(&self, r: &Ray) -> Option<IntersectionWithBSDF>;
 }
 
-pub fn estimated_total_radiance(ctx: &RenderContext, o: &Scene, r: &Ray) -> V3 {
+pub struct Photon {
+    pub d: Ray,
+    pub radiance: V3,
+}
+
+pub trait Light: Send + Sync {
+    fn sample_rad(&self, p: V3, sampler: &mut dyn Sampler) -> (f64, Photon);
+}
+
+pub fn estimated_total_radiance(
+    ctx: &RenderContext,
+    o: &Scene,
+    r: &Ray,
+    sampler: &mut dyn Sampler,
+) -> V3 {
     match o.object.intersect(r) {
         Some(p) => {
             if ctx.preview {
                 normalize_elems(math::normalize(&p.0.n))
             } else {
                 estimated_zero_bounce_radiance(r, &p)
-                    + estimated_at_least_one_bounce_radiance(ctx, o, r, &p, 0)
+                    + estimated_at_least_one_bounce_radiance(ctx, o, r, &p, 0, sampler)
             }
         }
         None => math::O,
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum Aov {
+    Normal,
+    Depth,
+    Position,
+}
+
+pub fn estimated_aov(aov: Aov, o: &Scene, r: &Ray) -> V3 {
+    match o.object.intersect(r) {
+        Some((intersection, _)) => match aov {
+            Aov::Normal => math::normalize(&intersection.n),
+            Aov::Depth => math::v(intersection.t, intersection.t, intersection.t),
+            Aov::Position => intersection.x,
+        },
+        None => math::O,
+    }
+}
+
 fn normalize_elems(s: V3) -> V3 {
     math::v(abs1(s.x), abs1(s.y), abs1(s.z))
 }
@@ -177,6 +266,8 @@ fn estimated_zero_bounce_radiance(r: &Ray, p: &IntersectionWithBSDF) -> V3 {
     p.1.radiance(w2o * r.d)
 }
 
+// Shading frame at a hit, with the tangent along x and the normal along z. Every BSDF is
+// evaluated in this frame.
 fn object_world_matrices_from_intersection(intersection: &Intersection) -> (math::M3, math::M3) {
     let o2w = math::M3 {
         v0: intersection.s,
@@ -195,6 +286,7 @@ fn _bounce(r: &Ray, intersection: &Intersection) -> Ray {
     Ray {
         x: intersection.x + math::EPS * d_bounce,
         d: d_bounce,
+        time: r.time,
     }
 }
 
@@ -203,29 +295,31 @@ fn estimated_one_bounce_radiance(
     s: &Scene,
     r: &Ray,
     p: &IntersectionWithBSDF,
+    sampler: &mut dyn Sampler,
 ) -> V3 {
     let o = &s.object;
     let (intersection, bsdf) = p;
-    let o2w = math::M3 {
-        v0: intersection.s,
-        v1: math::cross(&intersection.n, &intersection.s),
-        v2: intersection.n,
-    };
-    let w2o = o2w.t();
+    let (o2w, w2o) = object_world_matrices_from_intersection(intersection);
     let d_o = w2o * r.d;
 
-    let (pdf, wi_o) = (*bsdf).sample_wi(d_o);
-    let reflection = (*bsdf).bsdf(d_o, wi_o);
-    let wi_w = o2w * wi_o;
+    let sample = (*bsdf).sample_f(d_o, sampler);
+    if sample.pdf == 0. {
+        return math::O;
+    }
+    let wi_w = o2w * sample.wi;
     let starting_point = intersection.x + math::EPS * wi_w;
     let new_ray = Ray {
         x: starting_point,
         d: wi_w,
+        time: r.time,
     };
     match o.intersect(&new_ray) {
         None => math::O,
         Some(new_p) => {
-            1. / pdf * wi_o.z * estimated_zero_bounce_radiance(&new_ray, &new_p) * reflection
+            1. / sample.pdf
+                * sample.wi.z.abs()
+                * estimated_zero_bounce_radiance(&new_ray, &new_p)
+                * sample.f
         }
     }
 }
@@ -235,21 +329,27 @@ fn estimated_one_bounce_radiance_imp(
     s: &Scene,
     r: &Ray,
     p: &IntersectionWithBSDF,
+    sampler: &mut dyn Sampler,
 ) -> V3 {
     let (intersection, bsdf) = p;
-    let o2w = math::M3 {
-        v0: intersection.s,
-        v1: math::cross(&intersection.n, &intersection.s),
-        v2: intersection.n,
-    };
-    let w2o = o2w.t();
+    let (_o2w, w2o) = object_world_matrices_from_intersection(intersection);
     let d_o = w2o * r.d;
 
+    // Light samples never land on a delta lobe, a specular surface sees the lights through
+    // the next bounce instead.
+    if bsdf.is_specular() {
+        return math::O;
+    }
+
     let mut light_sum = math::O;
 
     for _ in 0..ctx.light_samples {
-        let (light_pdf, photon_sample) = s.light.sample_rad(intersection.x);
-        let shadow_ray = math::jitter_ray(photon_sample.d);
+        let (light_pdf, photon_sample) = s.light.sample_rad(intersection.x, sampler);
+        // Lights are static, the shadow ray has to see the occluders at the path's time.
+        let shadow_ray = Ray {
+            time: r.time,
+            ..math::jitter_ray(photon_sample.d)
+        };
 
         let mut obj_cos = math::dot(&intersection.n, &(-1.0 * photon_sample.d.d));
         if obj_cos < 0.0 {
@@ -275,6 +375,7 @@ fn estimated_at_least_one_bounce_radiance(
     r: &Ray,
     p: &IntersectionWithBSDF,
     bounce: i32,
+    sampler: &mut dyn Sampler,
 ) -> V3 {
     if bounce >= ctx.max_bounces {
         return math::O;
@@ -284,36 +385,49 @@ fn estimated_at_least_one_bounce_radiance(
         estimated_one_bounce_radiance_imp
     } else {
         estimated_one_bounce_radiance
-    })(ctx, s, r, p);
+    })(ctx, s, r, p, sampler);
 
-    let thresh: f64 = thread_rng().sample(Standard);
+    let thresh = sampler.get_1d();
     if thresh < ctx.termination_p {
         return one_bounce;
     }
     let (intersection, bsdf) = p;
-    let o2w = math::M3 {
-        v0: intersection.s,
-        v1: math::cross(&intersection.n, &intersection.s),
-        v2: intersection.n,
-    };
-    let w2o = o2w.t();
+    let (o2w, w2o) = object_world_matrices_from_intersection(intersection);
     let d_o = w2o * r.d;
 
-    let (pdf, wi_o) = (**bsdf).sample_wi(d_o);
-    let reflection = (*bsdf).bsdf(d_o, wi_o);
-    let wi_w = o2w * wi_o;
+    let sample = (**bsdf).sample_f(d_o, sampler);
+    if sample.pdf == 0. {
+        return one_bounce;
+    }
+    let wi_w = o2w * sample.wi;
     let starting_point = intersection.x + math::EPS * wi_w;
     let new_ray = Ray {
         x: starting_point,
         d: wi_w,
+        time: r.time,
     };
     match o.intersect(&new_ray) {
         None => one_bounce,
         Some(new_p) => {
-            1. / pdf / (1. - ctx.termination_p)
-                * wi_o.z
-                * estimated_at_least_one_bounce_radiance(ctx, s, &new_ray, &new_p, bounce + 1)
-                * reflection
+            // Light sampling can't see emitters through a specular bounce, so their emission
+            // is picked up here instead.
+            let emitted = if ctx.imp && sample.specular {
+                estimated_zero_bounce_radiance(&new_ray, &new_p)
+            } else {
+                math::O
+            };
+            1. / sample.pdf / (1. - ctx.termination_p)
+                * sample.wi.z.abs()
+                * (emitted
+                    + estimated_at_least_one_bounce_radiance(
+                        ctx,
+                        s,
+                        &new_ray,
+                        &new_p,
+                        bounce + 1,
+                        sampler,
+                    ))
+                * sample.f
                 + one_bounce
         }
     }
@@ -15,6 +15,33 @@ pub enum ObjLine {
     Blankline,
 }
 
+// Statements of a Wavefront material library, including the PBR extension (Pr, Pm, Ps, Pc,
+// Pcr, aniso). Texture maps keep their statement and the rest of the line.
+#[derive(Debug, Clone)]
+pub enum MtlLine {
+    Comment(String),
+    NewMaterial(String),
+    Ambient(f64, f64, f64),
+    Diffuse(f64, f64, f64),
+    Specular(f64, f64, f64),
+    Emission(f64, f64, f64),
+    TransmissionFilter(f64, f64, f64),
+    SpecularExponent(f64),
+    OpticalDensity(f64),
+    Dissolve(f64),
+    Transparency(f64),
+    Illumination(i32),
+    Roughness(f64),
+    Metallic(f64),
+    Sheen(f64),
+    ClearcoatThickness(f64),
+    ClearcoatRoughness(f64),
+    Anisotropy(f64),
+    AnisotropyRotation(f64),
+    Map(String, String),
+    Blankline,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum FaceVertex {
     Vertex(i32),
@@ -23,7 +50,7 @@ pub enum FaceVertex {
     VertexNormal(i32, i32),
 }
 
-pub fn obj_parser() -> impl Parser<char, Vec<ObjLine>, Error = Simple<char>> {
+fn float() -> impl Parser<char, f64, Error = Simple<char>> + Copy {
     let zero_padded_int = just('0')
         .repeated()
         .collect::<String>()
@@ -44,7 +71,11 @@ pub fn obj_parser() -> impl Parser<char, Vec<ObjLine>, Error = Simple<char>> {
         text::int(10).map(|a: String| a.parse::<f64>().unwrap()),
     ));
 
-    let float = just('-').ignore_then(float_pos).map(|x| -x).or(float_pos);
+    just('-').ignore_then(float_pos).map(|x| -x).or(float_pos)
+}
+
+pub fn obj_parser() -> impl Parser<char, Vec<ObjLine>, Error = Simple<char>> {
+    let float = float();
     let blank = empty().to(ObjLine::Blankline);
     let comment = just('#')
         .padded()
@@ -76,11 +107,12 @@ pub fn obj_parser() -> impl Parser<char, Vec<ObjLine>, Error = Simple<char>> {
         .collect()
         .map(|x: String| ObjLine::S(x.parse().unwrap()));
 
+    // The third texture coordinate is optional and usually left out.
     let vertex_texture = text::keyword("vt")
         .ignore_then(float.padded())
-        .then(float.padded())
         .then(float)
-        .map(|((x, y), z)| ObjLine::Texture(x, y, z));
+        .then(just(' ').repeated().ignore_then(float).or_not())
+        .map(|((x, y), z)| ObjLine::Texture(x, y, z.unwrap_or(0.)));
 
     let normal = text::keyword("vn")
         .ignore_then(float.padded())
@@ -150,6 +182,84 @@ pub fn obj_parser() -> impl Parser<char, Vec<ObjLine>, Error = Simple<char>> {
     line.separated_by(text::newline()).then_ignore(end())
 }
 
+pub fn mtl_parser() -> impl Parser<char, Vec<MtlLine>, Error = Simple<char>> {
+    let float = float();
+    // Statements end at the newline, only spaces and tabs separate arguments.
+    let space = || just(' ').or(just('\t')).repeated();
+    let rest_of_line = || {
+        text::newline()
+            .not()
+            .repeated()
+            .collect::<String>()
+            .map(|s| s.trim().to_string())
+    };
+    let color = |keyword: &'static str, line: fn(f64, f64, f64) -> MtlLine| {
+        text::keyword(keyword)
+            .ignore_then(float.padded_by(space()))
+            .then(float.padded_by(space()))
+            .then(float.padded_by(space()))
+            .map(move |((r, g), b)| line(r, g, b))
+    };
+    let scalar = |keyword: &'static str, line: fn(f64) -> MtlLine| {
+        text::keyword(keyword)
+            .ignore_then(float.padded_by(space()))
+            .map(line)
+    };
+
+    let blank = empty().to(MtlLine::Blankline);
+    let comment = just('#').ignore_then(rest_of_line()).map(MtlLine::Comment);
+    let new_material = text::keyword("newmtl")
+        .ignore_then(rest_of_line())
+        .map(MtlLine::NewMaterial);
+    let illumination = text::keyword("illum")
+        .ignore_then(text::int(10).padded_by(space()))
+        .map(|x: String| MtlLine::Illumination(x.parse().unwrap()));
+    let map = text::ident()
+        .try_map(|keyword: String, span| {
+            if keyword.starts_with("map_") || ["bump", "disp", "decal", "norm"].contains(&&*keyword)
+            {
+                Ok(keyword)
+            } else {
+                Err(Simple::custom(span, "unknown material statement"))
+            }
+        })
+        .then(rest_of_line())
+        .map(|(keyword, rest)| MtlLine::Map(keyword, rest));
+
+    let colors = choice((
+        color("Ka", MtlLine::Ambient),
+        color("Kd", MtlLine::Diffuse),
+        color("Ks", MtlLine::Specular),
+        color("Ke", MtlLine::Emission),
+        color("Tf", MtlLine::TransmissionFilter),
+    ));
+    let scalars = choice((
+        scalar("Ns", MtlLine::SpecularExponent),
+        scalar("Ni", MtlLine::OpticalDensity),
+        scalar("d", MtlLine::Dissolve),
+        scalar("Tr", MtlLine::Transparency),
+        scalar("Pr", MtlLine::Roughness),
+        scalar("Pm", MtlLine::Metallic),
+        scalar("Ps", MtlLine::Sheen),
+        scalar("Pc", MtlLine::ClearcoatThickness),
+        scalar("Pcr", MtlLine::ClearcoatRoughness),
+        scalar("aniso", MtlLine::Anisotropy),
+        scalar("anisor", MtlLine::AnisotropyRotation),
+    ));
+    let line = choice((
+        comment,
+        new_material,
+        colors,
+        scalars,
+        illumination,
+        map,
+        blank,
+    ));
+    line.padded_by(space())
+        .separated_by(text::newline())
+        .then_ignore(end())
+}
+
 #[derive(Debug, Clone)]
 struct ChumWrapper(Vec<Simple<char>>);
 
@@ -168,3 +278,11 @@ pub fn read_obj_file(path: &str) -> Result<Vec<ObjLine>, Box<dyn error::Error>>
         .map_err(|e| Box::new(ChumWrapper(e)))?;
     Ok(obj)
 }
+
+pub fn read_mtl_file(path: &str) -> Result<Vec<MtlLine>, Box<dyn error::Error>> {
+    let src = std::fs::read_to_string(path)?;
+    let mtl = mtl_parser()
+        .parse(src)
+        .map_err(|e| Box::new(ChumWrapper(e)))?;
+    Ok(mtl)
+}
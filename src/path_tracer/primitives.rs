@@ -3,11 +3,12 @@ use crate::math::{Intersectable, Ray, Sphere, Triangle, V3};
 use crate::path_tracer::bvh;
 use crate::path_tracer::bvh::BVHNode;
 use crate::path_tracer::obj::{FaceVertex, ObjLine};
+use crate::path_tracer::sampler::Sampler;
 use crate::path_tracer::{
-    sample_hemisphere, sample_sphere, IntersectionWithBSDF, Light, Object, Photon, BSDF,
+    flip_z, fresnel_dielectric, outgoing, radiance_scale, refract, sample_cosine_hemisphere,
+    sample_sphere, BSDFSample, IntersectionWithBSDF, Light, Object, Photon, BSDF,
 };
-use rand::distributions::Uniform;
-use rand::{thread_rng, Rng};
+use std::collections::HashMap;
 use std::f32::consts::PI;
 use std::sync::Arc;
 
@@ -16,12 +17,81 @@ pub struct Lambertian {
     pub reflectance: V3,
 }
 
+// Rough diffuse surface made of V-shaped Lambertian facets (Oren and Nayar 1994, in the
+// qualitative form). `sigma` is the standard deviation of the facet slopes in radians, 0
+// being Lambertian. Brightens towards the light at grazing angles like clay and cloth.
+#[derive(Clone, Copy, Debug)]
+pub struct OrenNayar {
+    pub reflectance: V3,
+    pub sigma: f64,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Emissive {
     pub emission: V3,
 }
 
-impl<B: BSDF> bvh::Bounded for Solid<B, Triangle> {
+// Perfect specular reflection.
+#[derive(Clone, Copy, Debug)]
+pub struct Mirror {
+    pub reflectance: V3,
+}
+
+// Smooth glass. Reflection and refraction are chosen by their Fresnel weights. The normal is
+// taken to point out of the object, with `ior` on the inside and air outside.
+#[derive(Clone, Copy, Debug)]
+pub struct Dielectric {
+    pub ior: f64,
+    pub reflectance: V3,
+    pub transmittance: V3,
+}
+
+impl Dielectric {
+    pub fn new(ior: f64) -> Self {
+        Self {
+            ior,
+            reflectance: math::v(1., 1., 1.),
+            transmittance: math::v(1., 1., 1.),
+        }
+    }
+}
+
+// Triangle of a mesh with texture coordinates, tangents and bitangents at its corners,
+// interpolated at hits. The tangents and bitangents are the change in position along u and
+// v, smoothed over the faces sharing a corner, for normal and bump maps. Mirrored uv
+// islands have bitangents against n × tangent.
+#[derive(Clone, Copy, Debug)]
+pub struct MeshTriangle {
+    pub triangle: Triangle,
+    pub uvs: [(f64, f64); 3],
+    pub tangents: [V3; 3],
+    pub bitangents: [V3; 3],
+}
+
+impl Intersectable for MeshTriangle {
+    fn intersect(&self, r: &Ray) -> Option<math::Intersection> {
+        let hit = self.triangle.intersect(r)?;
+        let (b1, b2) = hit.uv;
+        let b0 = 1. - b1 - b2;
+        let [(u0, v0), (u1, v1), (u2, v2)] = self.uvs;
+        let interpolate = |[a, b, c]: [V3; 3]| b0 * a + b1 * b + b2 * c;
+        let (dpdu, dpdv) = (interpolate(self.tangents), interpolate(self.bitangents));
+        let t = dpdu - math::dot(&dpdu, &hit.n) * hit.n;
+        Some(math::Intersection {
+            uv: (b0 * u0 + b1 * u1 + b2 * u2, b0 * v0 + b1 * v1 + b2 * v2),
+            s: if math::abs2(&t) > 1e-12 {
+                math::normalize(&t)
+            } else {
+                hit.s
+            },
+            dpdu,
+            dpdv,
+            ..hit
+        })
+    }
+}
+
+impl<B: BSDF + ?Sized> bvh::Bounded for Solid<B, Triangle> {
     fn get_bounds(&self) -> (V3, V3) {
         let t = &self.intersectable;
         let min = bvh::calculate_min(bvh::calculate_min(t.v1, t.v2), t.v0);
@@ -29,6 +99,15 @@ impl<B: BSDF> bvh::Bounded for Solid<B, Triangle> {
         (min, max)
     }
 }
+
+impl<B: BSDF + ?Sized> bvh::Bounded for Solid<B, MeshTriangle> {
+    fn get_bounds(&self) -> (V3, V3) {
+        let t = &self.intersectable.triangle;
+        let min = bvh::calculate_min(bvh::calculate_min(t.v1, t.v2), t.v0);
+        let max = bvh::calculate_max(bvh::calculate_max(t.v1, t.v2), t.v0);
+        (min, max)
+    }
+}
 pub fn obj_to_triangles(objs: &Vec<ObjLine>) -> Vec<Triangle> {
     let mut triangles: Vec<Triangle> = Vec::new();
     let mut vertices: Vec<V3> = Vec::new();
@@ -50,6 +129,119 @@ pub fn obj_to_triangles(objs: &Vec<ObjLine>) -> Vec<Triangle> {
     triangles
 }
 
+// Faces with their texture coordinates, in the order of obj_to_triangles. Tangents follow
+// u and are summed over the faces sharing a corner (Lengyel 2001). Faces without texture
+// coordinates get barycentric ones and the direction of their first edge.
+pub fn obj_to_mesh(objs: &Vec<ObjLine>) -> Vec<MeshTriangle> {
+    let mut vertices: Vec<V3> = Vec::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut faces: Vec<[FaceVertex; 3]> = Vec::new();
+    for obj_line in objs {
+        match obj_line {
+            ObjLine::Vertex(x, y, z) => vertices.push(math::v(*x, *y, *z)),
+            ObjLine::Texture(u, v, _) => uvs.push((*u, *v)),
+            ObjLine::Face(i, j, k) => faces.push([*i, *j, *k]),
+            _ => {}
+        }
+    }
+    // Corners are keyed by position and texture coordinate, seams keep their own tangents.
+    let face_corners = |face: &[FaceVertex; 3]| {
+        let [a, b, c] =
+            face.map(|f| get_texture_index_from_face(f).map(|t| (get_index_from_face(f), t)));
+        Some([a?, b?, c?])
+    };
+    // Change in position along u and v over a face, None where its uvs are degenerate.
+    let derivatives = |corners: [(usize, usize); 3]| {
+        let [p0, p1, p2] = corners.map(|(v, _)| vertices[v - 1]);
+        let [(u0, v0), (u1, v1), (u2, v2)] = corners.map(|(_, t)| uvs[t - 1]);
+        let (du1, dv1, du2, dv2) = (u1 - u0, v1 - v0, u2 - u0, v2 - v0);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let dpdu = (1. / det) * (dv2 * (p1 - p0) - dv1 * (p2 - p0));
+        let dpdv = (1. / det) * (du1 * (p2 - p0) - du2 * (p1 - p0));
+        Some((dpdu, dpdv))
+    };
+    let mut sums: HashMap<(usize, usize), (V3, V3, f64)> = HashMap::new();
+    for face in faces.iter() {
+        let Some(corners) = face_corners(face) else {
+            continue;
+        };
+        let Some((dpdu, dpdv)) = derivatives(corners) else {
+            continue;
+        };
+        for corner in corners {
+            let sum = sums.entry(corner).or_insert((math::O, math::O, 0.));
+            *sum = (sum.0 + dpdu, sum.1 + dpdv, sum.2 + 1.);
+        }
+    }
+    faces
+        .iter()
+        .map(|face| {
+            let [v0, v1, v2] = face.map(|f| vertices[get_index_from_face(f) - 1]);
+            let triangle = Triangle { v0, v1, v2 };
+            // Barycentric coordinates stand in for missing or degenerate uvs.
+            let edges = (v1 - v0, v2 - v0);
+            match face_corners(face) {
+                None => MeshTriangle {
+                    triangle,
+                    uvs: [(0., 0.), (1., 0.), (0., 1.)],
+                    tangents: [edges.0; 3],
+                    bitangents: [edges.1; 3],
+                },
+                Some(corners) => {
+                    let own = derivatives(corners).unwrap_or(edges);
+                    let smoothed = corners.map(|corner| match sums.get(&corner) {
+                        Some(&(dpdu, dpdv, n)) if math::abs2(&dpdu) > 1e-12 => {
+                            ((1. / n) * dpdu, (1. / n) * dpdv)
+                        }
+                        _ => own,
+                    });
+                    MeshTriangle {
+                        triangle,
+                        uvs: corners.map(|(_, t)| uvs[t - 1]),
+                        tangents: smoothed.map(|(dpdu, _)| dpdu),
+                        bitangents: smoothed.map(|(_, dpdv)| dpdv),
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+// Material named by the last usemtl before every face, in the order of obj_to_triangles.
+pub fn obj_face_materials(objs: &Vec<ObjLine>) -> Vec<Option<String>> {
+    let mut materials = Vec::new();
+    let mut current = None;
+    for obj_line in objs {
+        match obj_line {
+            ObjLine::UseMaterial(name) => current = Some(name.trim().to_string()),
+            ObjLine::Face(..) => materials.push(current.clone()),
+            _ => {}
+        }
+    }
+    materials
+}
+
+pub fn triangles_with_bsdfs_to_solid<B: BSDF + ?Sized + 'static, T: Intersectable>(
+    objs: Vec<(T, Arc<B>)>,
+    min_leaf_size: usize,
+) -> BVHNode<Solid<B, T>>
+where
+    Solid<B, T>: bvh::Bounded,
+{
+    bvh::BVHNode::new(
+        objs.into_iter()
+            .map(|(t, bsdf)| Solid {
+                bsdf,
+                intersectable: Arc::new(t),
+            })
+            .collect(),
+        min_leaf_size,
+    )
+}
+
 pub fn triangles_to_solid<B: BSDF + 'static>(
     objs: Vec<Triangle>,
     bsdf: Arc<B>,
@@ -80,6 +272,14 @@ pub fn obj_to_cup_solid<B: BSDF + 'static>(objs: &Vec<ObjLine>, bsdf: Arc<B>) ->
     }
 }
 
+fn get_texture_index_from_face(f: FaceVertex) -> Option<usize> {
+    match f {
+        FaceVertex::VertexTexture(_, t) => Some(t as usize),
+        FaceVertex::VertexTextureNormal(_, t, _) => Some(t as usize),
+        _ => None,
+    }
+}
+
 fn get_index_from_face(f: FaceVertex) -> usize {
     match f {
         FaceVertex::Vertex(i) => i as usize,
@@ -89,22 +289,88 @@ fn get_index_from_face(f: FaceVertex) -> usize {
     }
 }
 impl BSDF for Lambertian {
-    fn sample_wi(&self, _wo: V3) -> (f64, V3) {
-        sample_hemisphere()
+    // Cosine weighted on the side the ray came from.
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let (pdf, wi) = sample_cosine_hemisphere(sampler);
+        if wo.z > 0. {
+            (pdf, math::v(wi.x, wi.y, -wi.z))
+        } else {
+            (pdf, wi)
+        }
     }
 
-    fn bsdf(&self, _wo: V3, _wi: V3) -> V3 {
+    // Reflects back to the side the ray came from only.
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        if wo.z * wi.z >= 0. {
+            return math::O;
+        }
         (1. / PI as f64) * self.reflectance
     }
 
     fn radiance(&self, _wo: V3) -> V3 {
         math::O
     }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        if wo.z * wi.z < 0. {
+            wi.z.abs() / PI as f64
+        } else {
+            0.
+        }
+    }
+}
+
+impl BSDF for OrenNayar {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let (pdf, wi) = sample_cosine_hemisphere(sampler);
+        if wo.z > 0. {
+            (pdf, math::v(wi.x, wi.y, -wi.z))
+        } else {
+            (pdf, wi)
+        }
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        let (wo, flip) = outgoing(wo);
+        let wi = flip_z(wi, flip);
+        if wi.z <= 0. {
+            return math::O;
+        }
+        let sigma2 = self.sigma * self.sigma;
+        let a = 1. - sigma2 / (2. * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        let sin_i = (1. - wi.z * wi.z).max(0.).sqrt();
+        let sin_o = (1. - wo.z * wo.z).max(0.).sqrt();
+        // Cosine of the azimuth between the two directions in the tangent plane.
+        let max_cos = if sin_i > 1e-4 && sin_o > 1e-4 {
+            ((wi.x * wo.x + wi.y * wo.y) / (sin_i * sin_o)).max(0.)
+        } else {
+            0.
+        };
+        let (sin_alpha, tan_beta) = if wi.z > wo.z {
+            (sin_o, sin_i / wi.z)
+        } else {
+            (sin_i, sin_o / wo.z)
+        };
+        ((a + b * max_cos * sin_alpha * tan_beta) / PI as f64) * self.reflectance
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        if wo.z * wi.z < 0. {
+            wi.z.abs() / PI as f64
+        } else {
+            0.
+        }
+    }
 }
 
 impl BSDF for Emissive {
-    fn sample_wi(&self, _wo: V3) -> (f64, V3) {
-        sample_hemisphere()
+    fn sample_wi(&self, _wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        sample_cosine_hemisphere(sampler)
     }
 
     fn bsdf(&self, _wo: V3, _wi: V3) -> V3 {
@@ -114,44 +380,163 @@ impl BSDF for Emissive {
     fn radiance(&self, _wo: V3) -> V3 {
         self.emission
     }
+
+    fn pdf(&self, _wo: V3, wi: V3) -> f64 {
+        wi.z.max(0.) / PI as f64
+    }
+}
+
+impl BSDF for Mirror {
+    fn sample_wi(&self, wo: V3, _sampler: &mut dyn Sampler) -> (f64, V3) {
+        // `wo` points along the incoming ray, mirroring flips its normal component.
+        (1., math::v(wo.x, wo.y, -wo.z))
+    }
+
+    fn bsdf(&self, _wo: V3, _wi: V3) -> V3 {
+        math::O
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, _wo: V3, _wi: V3) -> f64 {
+        0.
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (pdf, wi) = self.sample_wi(wo, sampler);
+        BSDFSample {
+            wi,
+            pdf,
+            f: (1. / wi.z.abs()) * self.reflectance,
+            specular: true,
+        }
+    }
+}
+
+impl BSDF for Dielectric {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, _wo: V3, _wi: V3) -> V3 {
+        math::O
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, _wo: V3, _wi: V3) -> f64 {
+        0.
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let entering = wo.z < 0.;
+        let (eta_i, eta_t, n) = if entering {
+            (1., self.ior, math::B3)
+        } else {
+            (self.ior, 1., -math::B3)
+        };
+        let cos_i = -math::dot(&n, &wo);
+        let fresnel = fresnel_dielectric(cos_i, eta_i, eta_t);
+        let refracted = refract(wo, n, eta_i / eta_t);
+        match refracted {
+            Some(wi) if sampler.get_1d() >= fresnel => {
+                let scale = (1. - fresnel) * radiance_scale(eta_t / eta_i);
+                BSDFSample {
+                    wi,
+                    pdf: 1. - fresnel,
+                    f: (scale / wi.z.abs()) * self.transmittance,
+                    specular: true,
+                }
+            }
+            _ => {
+                let wi = math::v(wo.x, wo.y, -wo.z);
+                BSDFSample {
+                    wi,
+                    pdf: fresnel,
+                    f: (fresnel / wi.z.abs()) * self.reflectance,
+                    specular: true,
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct Solid<B: BSDF, I: Intersectable> {
+pub struct Solid<B: BSDF + ?Sized, I: Intersectable> {
     pub bsdf: Arc<B>,
     pub intersectable: Arc<I>,
 }
 impl<B: BSDF + 'static, I: Intersectable> Object for Solid<B, I> {
     fn intersect(&self, r: &Ray) -> Option<IntersectionWithBSDF> {
-        self.intersectable
-            .intersect(r)
-            .map(|intersection| (intersection, self.bsdf.clone() as Arc<dyn BSDF>))
+        let hit = self.intersectable.intersect(r)?;
+        Some(shade(hit, self.bsdf.clone()))
+    }
+}
+
+// Solids with materials picked at runtime, e.g. per face from a material library.
+impl<I: Intersectable> Object for Solid<dyn BSDF, I> {
+    fn intersect(&self, r: &Ray) -> Option<IntersectionWithBSDF> {
+        let hit = self.intersectable.intersect(r)?;
+        Some(shade(hit, self.bsdf.clone()))
     }
 }
 
+// Hits are still in the solid's own space, which textures are defined in. Normal maps tilt
+// the frame first, the material is then read at the tilted hit.
+fn shade(mut hit: math::Intersection, bsdf: Arc<dyn BSDF>) -> IntersectionWithBSDF {
+    bsdf.perturb(&mut hit);
+    let material = bsdf.at(&hit).unwrap_or(bsdf);
+    (hit, material)
+}
+
 pub struct TransformedObject<O: Object> {
     pub wrapped: Arc<O>,
-    pub transform: math::Transform,
+    pub transform: math::AnimatedTransform,
 }
 
 impl<O: Object> TransformedObject<O> {
     pub fn new(wrapped: Arc<O>, transform: math::Transform) -> Self {
+        Self {
+            wrapped,
+            transform: math::AnimatedTransform::fixed(transform),
+        }
+    }
+
+    // The transform is picked by each ray's time.
+    pub fn animated(wrapped: Arc<O>, transform: math::AnimatedTransform) -> Self {
         Self { wrapped, transform }
     }
 }
 
 impl<O: Object> Object for TransformedObject<O> {
     fn intersect(&self, r: &Ray) -> Option<IntersectionWithBSDF> {
-        let inverted = self.transform.invert();
+        let transform = self.transform.at(r.time);
+        let inverted = transform.invert();
         self.wrapped
-            .intersect(&math::transform_ray(self.transform, r))
-            .map(|(math::Intersection { x, n, s, t }, b)| {
+            .intersect(&math::transform_ray(transform, r))
+            .map(|(hit, b)| {
                 (
                     math::Intersection {
-                        x: inverted.do_affine(x),
-                        n: inverted.do_linear(n),
-                        s: inverted.do_linear(s),
-                        t,
+                        x: inverted.do_affine(hit.x),
+                        n: inverted.do_linear(hit.n),
+                        s: inverted.do_linear(hit.s),
+                        t: hit.t,
+                        uv: hit.uv,
+                        dpdu: inverted.do_linear(hit.dpdu),
+                        dpdv: inverted.do_linear(hit.dpdv),
                     },
                     b,
                 )
@@ -159,6 +544,54 @@ impl<O: Object> Object for TransformedObject<O> {
     }
 }
 
+// Steps per keyframe interval when bounding moving objects. Rotations bulge out between
+// steps, which the padding covers for up to a half turn per interval.
+const MOTION_BOUND_STEPS: usize = 32;
+
+impl<O: Object + bvh::Bounded> bvh::Bounded for TransformedObject<O> {
+    fn get_bounds(&self) -> (V3, V3) {
+        let (min, max) = self.wrapped.get_bounds();
+        let corners: Vec<V3> = (0..8)
+            .map(|i| {
+                math::v(
+                    if i & 1 == 0 { min.x } else { max.x },
+                    if i & 2 == 0 { min.y } else { max.y },
+                    if i & 4 == 0 { min.z } else { max.z },
+                )
+            })
+            .collect();
+        let keyframes = self.transform.keyframes();
+        let mut times = vec![keyframes[0].0];
+        for pair in keyframes.windows(2) {
+            let (t0, t1) = (pair[0].0, pair[1].0);
+            times.extend(
+                (1..=MOTION_BOUND_STEPS)
+                    .map(|i| t0 + (t1 - t0) * i as f64 / MOTION_BOUND_STEPS as f64),
+            );
+        }
+        let mut bounds = (
+            math::v(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            math::v(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        );
+        for time in times {
+            let object_to_world = self.transform.at(time).invert();
+            for corner in corners.iter() {
+                let p = object_to_world.do_affine(*corner);
+                bounds = (
+                    bvh::calculate_min(bounds.0, p),
+                    bvh::calculate_max(bounds.1, p),
+                );
+            }
+        }
+        if self.transform.is_animated() {
+            let pad = 0.01 * math::dist(&bounds.0, &bounds.1);
+            let pad = math::v(pad, pad, pad);
+            bounds = (bounds.0 - pad, bounds.1 + pad);
+        }
+        bounds
+    }
+}
+
 pub struct Cup {
     pub objects: Vec<Arc<dyn Object>>,
 }
@@ -169,10 +602,10 @@ impl Object for Cup {
         for object in self.objects.iter() {
             let intersection = object.intersect(r);
             match (&ret, &intersection) {
-                (Some((ret_intersection, _)), Some((new_intersection, _))) => {
-                    if ret_intersection.t > new_intersection.t {
-                        ret = intersection;
-                    }
+                (Some((ret_intersection, _)), Some((new_intersection, _)))
+                    if ret_intersection.t > new_intersection.t =>
+                {
+                    ret = intersection;
                 }
                 (None, Some(_)) => ret = intersection,
                 _ => {}
@@ -189,8 +622,8 @@ pub struct SphereLight {
 }
 
 impl Light for SphereLight {
-    fn sample_rad(&self, p: V3) -> (f64, Photon) {
-        let v = sample_sphere();
+    fn sample_rad(&self, p: V3, sampler: &mut dyn Sampler) -> (f64, Photon) {
+        let v = sample_sphere(sampler);
         let light_surface_point = self.sphere.r * v + self.sphere.x;
         let dir = math::normalize(&(p - light_surface_point));
         let mut cos_dir = math::dot(&dir, &v);
@@ -204,6 +637,7 @@ impl Light for SphereLight {
                 d: math::Ray {
                     d: dir,
                     x: light_surface_point,
+                    time: 0.,
                 },
                 radiance: cos_dir * self.e.emission,
             },
@@ -216,7 +650,7 @@ pub struct CupLight {
 }
 
 impl Light for CupLight {
-    fn sample_rad(&self, p: V3) -> (f64, Photon) {
+    fn sample_rad(&self, p: V3, sampler: &mut dyn Sampler) -> (f64, Photon) {
         let num_lights = self.lights.len();
         if num_lights == 0 {
             return (
@@ -225,14 +659,15 @@ impl Light for CupLight {
                     d: Ray {
                         x: math::O,
                         d: math::O,
+                        time: 0.,
                     },
                     radiance: math::O,
                 },
             );
         }
-        let index = thread_rng().sample(Uniform::new(0, num_lights));
+        let index = ((sampler.get_1d() * num_lights as f64) as usize).min(num_lights - 1);
         let light = &self.lights[index];
-        let (pdf, photon) = light.sample_rad(p);
+        let (pdf, photon) = light.sample_rad(p, sampler);
         (pdf / (num_lights as f64), photon)
     }
 }
@@ -0,0 +1,416 @@
+use crate::color::luminance;
+use crate::math;
+use crate::math::V3;
+use crate::path_tracer::microfacet::{MicrofacetDistribution, RoughDielectric, TrowbridgeReitz};
+use crate::path_tracer::obj::MtlLine;
+use crate::path_tracer::sampler::Sampler;
+use crate::path_tracer::{flip_z, outgoing, reflect, sample_cosine_hemisphere, BSDFSample, BSDF};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+// Keeps the specular lobes rough enough to be evaluated, perfectly smooth principled
+// surfaces would have to be delta distributions.
+const MIN_ALPHA: f64 = 1e-3;
+
+// Disney's principled BSDF (Burley 2012, 2015). All parameters are in [0, 1] except `ior`.
+// Diffuse, sheen, specular and clearcoat reflect on both sides of the surface, transmission
+// goes through a rough dielectric with the normal pointing out of the object.
+#[derive(Clone, Copy, Debug)]
+pub struct Principled {
+    pub base_color: V3,
+    pub metallic: f64,
+    pub roughness: f64,
+    // Reflectance of dielectrics at normal incidence, 0.5 being 4%.
+    pub specular: f64,
+    // Tints dielectric specular towards the base colour.
+    pub specular_tint: f64,
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_gloss: f64,
+    pub transmission: f64,
+    pub ior: f64,
+    // Stretches highlights along the shading tangent.
+    pub anisotropic: f64,
+    pub emission: V3,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Self {
+            base_color: math::v(0.8, 0.8, 0.8),
+            metallic: 0.,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.,
+            sheen: 0.,
+            sheen_tint: 0.5,
+            clearcoat: 0.,
+            clearcoat_gloss: 1.,
+            transmission: 0.,
+            ior: 1.5,
+            anisotropic: 0.,
+            emission: math::O,
+        }
+    }
+}
+
+// Lobe weights in the sum that makes up the BSDF, and the probabilities of sampling each.
+struct Lobes {
+    diffuse: f64,
+    specular: f64,
+    clearcoat: f64,
+    transmission: f64,
+    diffuse_p: f64,
+    specular_p: f64,
+    clearcoat_p: f64,
+    transmission_p: f64,
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1. - cos).clamp(0., 1.).powi(5)
+}
+
+fn lerp(t: f64, a: V3, b: V3) -> V3 {
+    (1. - t) * a + t * b
+}
+
+// Berry's distribution used by the clearcoat, with a longer tail than GGX.
+fn gtr1(cos_h: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    (a2 - 1.) / (PI * a2.ln() * (1. + (a2 - 1.) * cos_h * cos_h))
+}
+
+impl Principled {
+    // Lobes seen from a direction at `cos_o` to the normal.
+    fn lobes(&self, cos_o: f64) -> Lobes {
+        let diffuse = (1. - self.metallic) * (1. - self.transmission);
+        let transmission = (1. - self.metallic) * self.transmission;
+        let specular = 1. - transmission;
+        let clearcoat = 0.25 * self.clearcoat;
+        let diffuse_p = diffuse * luminance(self.base_color).max(0.);
+        // Dielectric specular reflects little outside grazing angles, sampling it by its
+        // Fresnel reflectance keeps it from taking most samples.
+        let fresnel = lerp(
+            schlick_weight(cos_o.abs()),
+            self.specular_f0(),
+            math::v(1., 1., 1.),
+        );
+        let specular_p = specular * luminance(fresnel).max(0.);
+        let total = diffuse_p + specular_p + clearcoat + transmission;
+        let (diffuse_p, total) = if total > 0. {
+            (diffuse_p, total)
+        } else {
+            (1., 1.)
+        };
+        Lobes {
+            diffuse,
+            specular,
+            clearcoat,
+            transmission,
+            diffuse_p: diffuse_p / total,
+            specular_p: specular_p / total,
+            clearcoat_p: clearcoat / total,
+            transmission_p: transmission / total,
+        }
+    }
+
+    fn tint(&self) -> V3 {
+        let l = luminance(self.base_color);
+        if l > 0. {
+            (1. / l) * self.base_color
+        } else {
+            math::v(1., 1., 1.)
+        }
+    }
+
+    fn distribution(&self) -> TrowbridgeReitz {
+        let aspect = (1. - 0.9 * self.anisotropic).sqrt();
+        let alpha = self.roughness * self.roughness;
+        TrowbridgeReitz {
+            alpha_x: (alpha / aspect).max(MIN_ALPHA),
+            alpha_y: (alpha * aspect).max(MIN_ALPHA),
+        }
+    }
+
+    fn clearcoat_alpha(&self) -> f64 {
+        0.1 * (1. - self.clearcoat_gloss) + 0.001 * self.clearcoat_gloss
+    }
+
+    fn transmission_lobe(&self) -> RoughDielectric<TrowbridgeReitz> {
+        RoughDielectric {
+            transmittance: self.base_color,
+            ..RoughDielectric::new(self.ior, self.distribution())
+        }
+    }
+
+    // Reflectance of the specular lobe at normal incidence.
+    fn specular_f0(&self) -> V3 {
+        let dielectric =
+            (0.08 * self.specular) * lerp(self.specular_tint, math::v(1., 1., 1.), self.tint());
+        lerp(self.metallic, dielectric, self.base_color)
+    }
+
+    // Reflection lobes, with `wo` and `wi` pointing away from the surface on its upper side.
+    fn f_reflection(&self, lobes: &Lobes, wo: V3, wi: V3) -> V3 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return math::O;
+        }
+        let wm = math::normalize(&(wo + wi));
+        let cos_d = math::dot(&wi, &wm);
+        let mut f = math::O;
+        if lobes.diffuse > 0. {
+            let fd90 = 0.5 + 2. * self.roughness * cos_d * cos_d;
+            let fd = (1. + (fd90 - 1.) * schlick_weight(wi.z))
+                * (1. + (fd90 - 1.) * schlick_weight(wo.z));
+            let sheen = (self.sheen * schlick_weight(cos_d))
+                * lerp(self.sheen_tint, math::v(1., 1., 1.), self.tint());
+            f = f + lobes.diffuse * ((fd / PI) * self.base_color + sheen);
+        }
+        if lobes.specular > 0. {
+            let distribution = self.distribution();
+            let f0 = self.specular_f0();
+            let fresnel = lerp(schlick_weight(cos_d), f0, math::v(1., 1., 1.));
+            let dg = distribution.d(wm) * distribution.g(wo, wi);
+            f = f + (lobes.specular * dg / (4. * wo.z * wi.z)) * fresnel;
+        }
+        if lobes.clearcoat > 0. {
+            let d = gtr1(wm.z, self.clearcoat_alpha());
+            let fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+            let coat = TrowbridgeReitz {
+                alpha_x: 0.25,
+                alpha_y: 0.25,
+            };
+            let g = coat.g1(wo) * coat.g1(wi);
+            let c = lobes.clearcoat * d * fresnel * g / (4. * wo.z * wi.z);
+            f = f + math::v(c, c, c);
+        }
+        f
+    }
+
+    fn pdf_reflection(&self, lobes: &Lobes, wo: V3, wi: V3) -> f64 {
+        if wo.z <= 0. || wi.z <= 0. {
+            return 0.;
+        }
+        let wm = math::normalize(&(wo + wi));
+        let cos_om = math::dot(&wo, &wm).abs();
+        lobes.diffuse_p * wi.z / PI
+            + lobes.specular_p * self.distribution().pdf(wo, wm) / (4. * cos_om)
+            + lobes.clearcoat_p * gtr1(wm.z, self.clearcoat_alpha()) * wm.z / (4. * cos_om)
+    }
+}
+
+impl BSDF for Principled {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        let sample = self.sample_f(wo, sampler);
+        (sample.pdf, sample.wi)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        let (v, flip) = outgoing(wo);
+        let lobes = self.lobes(v.z);
+        let mut f = self.f_reflection(&lobes, v, flip_z(wi, flip));
+        if lobes.transmission > 0. {
+            f = f + lobes.transmission * self.transmission_lobe().bsdf(wo, wi);
+        }
+        f
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        self.emission
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        let (v, flip) = outgoing(wo);
+        let lobes = self.lobes(v.z);
+        let mut pdf = self.pdf_reflection(&lobes, v, flip_z(wi, flip));
+        if lobes.transmission_p > 0. {
+            pdf += lobes.transmission_p * self.transmission_lobe().pdf(wo, wi);
+        }
+        pdf
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        let (v, flip) = outgoing(wo);
+        let lobes = self.lobes(v.z);
+        let mut u = sampler.get_1d();
+        let wi = if u < lobes.transmission_p {
+            let sample = self.transmission_lobe().sample_f(wo, sampler);
+            // Glass without a change in index passes light straight through. Failed samples
+            // are passed on as well, their direction wasn't drawn from the pdf.
+            if sample.specular || sample.pdf == 0. {
+                return BSDFSample {
+                    pdf: lobes.transmission_p * sample.pdf,
+                    f: lobes.transmission * sample.f,
+                    ..sample
+                };
+            }
+            sample.wi
+        } else {
+            u -= lobes.transmission_p;
+            let wi = if u < lobes.diffuse_p {
+                sample_cosine_hemisphere(sampler).1
+            } else if u < lobes.diffuse_p + lobes.specular_p {
+                reflect(v, self.distribution().sample_wm(v, sampler.get_2d()))
+            } else {
+                let (u0, u1) = sampler.get_2d();
+                let a2 = self.clearcoat_alpha().powi(2);
+                let cos_h = ((1. - a2.powf(1. - u0)) / (1. - a2)).max(0.).sqrt();
+                let sin_h = (1. - cos_h * cos_h).max(0.).sqrt();
+                let phi = 2. * PI * u1;
+                let wm = math::v(sin_h * phi.cos(), sin_h * phi.sin(), cos_h);
+                reflect(v, wm)
+            };
+            flip_z(wi, flip)
+        };
+        BSDFSample {
+            wi,
+            pdf: self.pdf(wo, wi),
+            f: self.bsdf(wo, wi),
+            specular: false,
+        }
+    }
+}
+
+// Principled materials of a material library by name. The PBR extension maps directly,
+// classic statements are converted the way common exporters write them: Ns becomes
+// roughness, Ks specular, and dissolve (or Tr) transmission.
+pub fn mtl_to_materials(lines: &[MtlLine]) -> HashMap<String, Principled> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Principled)> = None;
+    for line in lines {
+        if let MtlLine::NewMaterial(name) = line {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material);
+            }
+            current = Some((name.clone(), Principled::default()));
+            continue;
+        }
+        let Some((_, m)) = current.as_mut() else {
+            continue;
+        };
+        match *line {
+            MtlLine::Diffuse(r, g, b) => m.base_color = math::v(r, g, b),
+            MtlLine::Specular(r, g, b) => m.specular = luminance(math::v(r, g, b)).clamp(0., 1.),
+            MtlLine::Emission(r, g, b) => m.emission = math::v(r, g, b),
+            MtlLine::SpecularExponent(ns) => {
+                m.roughness = 1. - (ns.clamp(0., 1000.) / 1000.).sqrt()
+            }
+            MtlLine::OpticalDensity(ni) if ni >= 1. => m.ior = ni,
+            MtlLine::Dissolve(d) => m.transmission = (1. - d).clamp(0., 1.),
+            MtlLine::Transparency(tr) => m.transmission = tr.clamp(0., 1.),
+            MtlLine::Roughness(r) => m.roughness = r.clamp(0., 1.),
+            MtlLine::Metallic(x) => m.metallic = x.clamp(0., 1.),
+            MtlLine::Sheen(x) => m.sheen = x.clamp(0., 1.),
+            MtlLine::ClearcoatThickness(x) => m.clearcoat = x.clamp(0., 1.),
+            MtlLine::ClearcoatRoughness(x) => m.clearcoat_gloss = 1. - x.clamp(0., 1.),
+            MtlLine::Anisotropy(x) => m.anisotropic = x.clamp(0., 1.),
+            _ => {}
+        }
+    }
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+    materials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tracer::testing::{albedo, incoming, pdf_integral, samples};
+
+    const COUNT: usize = 20000;
+    const INTEGRAL_COUNT: usize = 2_000_000;
+
+    fn materials() -> Vec<Principled> {
+        vec![
+            Principled::default(),
+            Principled {
+                metallic: 1.,
+                anisotropic: 0.5,
+                ..Principled::default()
+            },
+            Principled {
+                sheen: 1.,
+                clearcoat: 1.,
+                clearcoat_gloss: 0.5,
+                ..Principled::default()
+            },
+            // Narrow refraction lobes need many more directions to integrate.
+            Principled {
+                transmission: 1.,
+                roughness: 0.8,
+                ..Principled::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn principled_pdf_matches_its_samples() {
+        for (i, material) in materials().iter().enumerate() {
+            for cos_theta in [1., 0.5, 0.1, -0.5] {
+                let wo = incoming(cos_theta);
+                let pdf = |wi: V3| material.pdf(wo, wi);
+                let samples = samples(material, wo, COUNT);
+                for sample in samples.iter().filter(|s| s.pdf > 0.) {
+                    assert!((sample.pdf - pdf(sample.wi)).abs() <= 1e-9 * sample.pdf);
+                }
+                let kept = samples.iter().filter(|s| s.pdf > 0.).count() as f64 / COUNT as f64;
+                let integral = pdf_integral(pdf, INTEGRAL_COUNT);
+                assert!(
+                    (integral - kept).abs() < 0.01,
+                    "{} {} {} {}",
+                    i,
+                    cos_theta,
+                    integral,
+                    kept
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn principled_conserves_energy() {
+        let white = math::v(1., 1., 1.);
+        for roughness in [0.1, 0.5, 1.] {
+            let metal = Principled {
+                base_color: white,
+                roughness,
+                metallic: 1.,
+                ..Principled::default()
+            };
+            let glass = Principled {
+                base_color: white,
+                roughness,
+                transmission: 1.,
+                ..Principled::default()
+            };
+            let plastic = Principled {
+                base_color: white,
+                roughness,
+                ..Principled::default()
+            };
+            for cos_theta in [1., 0.5, 0.1] {
+                let wo = incoming(cos_theta);
+                let metal = albedo(&metal, wo, COUNT);
+                assert!(metal.x <= 1.01, "{} {} {:?}", roughness, cos_theta, metal);
+                if roughness <= 0.1 {
+                    assert!(metal.x > 0.95, "{} {} {:?}", roughness, cos_theta, metal);
+                }
+                let glass = albedo(&glass, wo, COUNT);
+                assert!(glass.x <= 1.01, "{} {} {:?}", roughness, cos_theta, glass);
+                // Burley's diffuse isn't energy conserving, rough surfaces get brighter towards
+                // grazing angles to match measured retro-reflection.
+                let plastic = albedo(&plastic, wo, COUNT);
+                let bound = if cos_theta < 0.5 { 1.5 } else { 1.1 };
+                assert!(
+                    plastic.x <= bound,
+                    "{} {} {:?}",
+                    roughness,
+                    cos_theta,
+                    plastic
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use crate::math::V3;
+use crate::path_tracer::film::{Film, FilmTile};
+use crate::path_tracer::sampler::Sampler;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub tile_size: usize,
+    pub time_budget: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.tiles_total == 0 {
+            1.
+        } else {
+            self.tiles_done as f64 / self.tiles_total as f64
+        }
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        if self.tiles_done == 0 {
+            return None;
+        }
+        let remaining = (self.tiles_total - self.tiles_done) as f64;
+        Some(self.elapsed.mul_f64(remaining / self.tiles_done as f64))
+    }
+}
+
+pub fn tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut ret = Vec::new();
+    for y0 in (0..height).step_by(tile_size) {
+        for x0 in (0..width).step_by(tile_size) {
+            ret.push(Tile {
+                x0,
+                y0,
+                x1: (x0 + tile_size).min(width),
+                y1: (y0 + tile_size).min(height),
+            });
+        }
+    }
+    ret
+}
+
+// Renders every pixel sample through `radiance`, which gets continuous raster coordinates and
+// the sampler positioned right after the pixel jitter dimensions. Tiles that are skipped
+// because of cancellation or the time budget stay black. Returns whether every tile finished.
+pub fn render<S, F, P>(
+    settings: &RenderSettings,
+    film: &mut Film,
+    cancel: &AtomicBool,
+    on_progress: P,
+    new_sampler: S,
+    radiance: F,
+) -> bool
+where
+    S: Fn() -> Box<dyn Sampler> + Sync,
+    F: Fn(f64, f64, &mut dyn Sampler) -> V3 + Sync,
+    P: Fn(&Progress) + Sync,
+{
+    let start = Instant::now();
+    let tiles = tiles(film.width, film.height, settings.tile_size);
+    let tiles_total = tiles.len();
+    let tiles_done = AtomicUsize::new(0);
+    let should_stop = || {
+        cancel.load(Ordering::Relaxed)
+            || settings
+                .time_budget
+                .is_some_and(|budget| start.elapsed() > budget)
+    };
+
+    let film_ref = &*film;
+    let rendered: Vec<Option<FilmTile>> = tiles
+        .par_iter()
+        .map(|tile| {
+            if should_stop() {
+                return None;
+            }
+            let mut film_tile = film_ref.tile(tile);
+            let mut sampler = new_sampler();
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    for sample in 0..sampler.samples_per_pixel() {
+                        sampler.start_pixel_sample(x, y, sample);
+                        let (jx, jy) = sampler.get_2d();
+                        let (px, py) = (x as f64 + jx, y as f64 + jy);
+                        let l = radiance(px, py, sampler.as_mut());
+                        film_tile.add_sample(px, py, l);
+                    }
+                }
+            }
+            let done = tiles_done.fetch_add(1, Ordering::Relaxed) + 1;
+            on_progress(&Progress {
+                tiles_done: done,
+                tiles_total,
+                elapsed: start.elapsed(),
+            });
+            Some(film_tile)
+        })
+        .collect();
+
+    // Merging in tile order keeps the floating point sums independent of thread scheduling.
+    let mut completed = true;
+    for film_tile in rendered {
+        match film_tile {
+            None => completed = false,
+            Some(film_tile) => film.merge_tile(film_tile),
+        }
+    }
+    completed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+    use crate::path_tracer::film::{BoxFilter, Filter, TentFilter};
+    use crate::path_tracer::primitives::{Cup, Emissive, Lambertian, Solid, SphereLight};
+    use crate::path_tracer::sampler::IndependentSampler;
+    use crate::path_tracer::{estimated_total_radiance, RenderContext, Scene};
+    use std::sync::Arc;
+
+    const SIZE: usize = 24;
+
+    fn settings(tile_size: usize) -> RenderSettings {
+        RenderSettings {
+            tile_size,
+            time_budget: None,
+        }
+    }
+
+    // A diffuse ball on a floor under a small light, looked at from the origin.
+    fn render_scene(tile_size: usize, threads: usize, filter: Arc<dyn Filter>) -> Vec<V3> {
+        let grey = Arc::new(Lambertian {
+            reflectance: math::v(0.7, 0.7, 0.7),
+        });
+        let ball = Solid {
+            bsdf: grey.clone(),
+            intersectable: Arc::new(math::Sphere {
+                x: math::v(0., 0., 4.),
+                r: 1.,
+            }),
+        };
+        let floor = Solid {
+            bsdf: grey,
+            intersectable: Arc::new(math::Plane {
+                x: math::v(0., -1., 0.),
+                n: math::v(0., 1., 0.),
+                s: math::v(1., 0., 0.),
+            }),
+        };
+        let scene = Scene {
+            object: Box::new(Cup {
+                objects: vec![Arc::new(ball), Arc::new(floor)],
+            }),
+            light: Box::new(SphereLight {
+                sphere: math::Sphere {
+                    x: math::v(2., 3., 2.),
+                    r: 0.5,
+                },
+                e: Emissive {
+                    emission: math::v(50., 50., 50.),
+                },
+            }),
+        };
+        let ctx = RenderContext {
+            imp: true,
+            max_bounces: 3,
+            termination_p: 0.2,
+            light_samples: 1,
+            preview: false,
+        };
+        let mut film = Film::new(SIZE, SIZE, filter);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let completed = pool.install(|| {
+            render(
+                &settings(tile_size),
+                &mut film,
+                &AtomicBool::new(false),
+                |_| {},
+                || Box::new(IndependentSampler::new(4, 1)),
+                |px, py, sampler| {
+                    let (x, y) = (px / SIZE as f64 - 0.5, 0.5 - py / SIZE as f64);
+                    let ray = math::Ray {
+                        x: math::O,
+                        d: math::normalize(&math::v(x, y, 1.)),
+                        time: 0.,
+                    };
+                    estimated_total_radiance(&ctx, &scene, &ray, sampler)
+                },
+            )
+        });
+        assert!(completed);
+        film.pixels()
+    }
+
+    fn assert_identical(a: &[V3], b: &[V3]) {
+        assert!(a.iter().any(|p| p.x > 0.));
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!(a.x.to_bits(), b.x.to_bits());
+            assert_eq!(a.y.to_bits(), b.y.to_bits());
+            assert_eq!(a.z.to_bits(), b.z.to_bits());
+        }
+    }
+
+    #[test]
+    fn renders_are_independent_of_threads() {
+        // A filter wider than a pixel splats samples across tile borders.
+        let filter = Arc::new(TentFilter { radius: 1.5 });
+        assert_identical(
+            &render_scene(8, 1, filter.clone()),
+            &render_scene(8, 4, filter),
+        );
+    }
+
+    // Tiles are summed into the film one by one, so only filters that keep samples in their
+    // own pixel add up in the same order for every tile size.
+    #[test]
+    fn renders_are_independent_of_tile_size() {
+        let filter = Arc::new(BoxFilter { radius: 0.5 });
+        assert_identical(
+            &render_scene(8, 3, filter.clone()),
+            &render_scene(16, 3, filter),
+        );
+    }
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        let mut covered = vec![0; 10 * 7];
+        for tile in tiles(10, 7, 3) {
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    covered[y * 10 + x] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn pixels_land_where_they_were_rendered() {
+        for tile_size in [1, 3, 4, 16] {
+            let mut film = Film::new(10, 7, Arc::new(BoxFilter { radius: 0.5 }));
+            let completed = render(
+                &settings(tile_size),
+                &mut film,
+                &AtomicBool::new(false),
+                |_| {},
+                || Box::new(IndependentSampler::new(2, 1)),
+                |px, py, _| math::v(px.floor(), py.floor(), 1.),
+            );
+            assert!(completed);
+            let pixels = film.pixels();
+            for y in 0..7 {
+                for x in 0..10 {
+                    let p = pixels[x + y * 10];
+                    assert_eq!((p.x, p.y, p.z), (x as f64, y as f64, 1.));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn progress_reaches_every_tile() {
+        let reported = AtomicUsize::new(0);
+        let mut film = Film::new(10, 7, Arc::new(BoxFilter { radius: 0.5 }));
+        let completed = render(
+            &settings(4),
+            &mut film,
+            &AtomicBool::new(false),
+            |progress| {
+                reported.fetch_max(progress.tiles_done, Ordering::Relaxed);
+            },
+            || Box::new(IndependentSampler::new(1, 1)),
+            |_, _, _| math::O,
+        );
+        assert!(completed);
+        assert_eq!(reported.into_inner(), tiles(10, 7, 4).len());
+    }
+
+    #[test]
+    fn cancelled_renders_are_black_and_incomplete() {
+        let mut film = Film::new(10, 7, Arc::new(BoxFilter { radius: 0.5 }));
+        let completed = render(
+            &settings(4),
+            &mut film,
+            &AtomicBool::new(true),
+            |_| {},
+            || Box::new(IndependentSampler::new(1, 1)),
+            |_, _, _| math::v(1., 1., 1.),
+        );
+        assert!(!completed);
+        assert!(film
+            .pixels()
+            .iter()
+            .all(|p| p.x == 0. && p.y == 0. && p.z == 0.));
+    }
+}
@@ -0,0 +1,423 @@
+use crate::math::mix64;
+use crate::path_tracer::rng::Pcg32;
+use rand::Rng;
+
+const ONE_MINUS_EPSILON: f64 = 1. - f64::EPSILON / 2.;
+
+// Hands out sample dimensions for one pixel sample at a time. Every consumer (camera, BSDFs,
+// lights, russian roulette) takes its dimensions in the same order, so a given
+// (pixel, sample index) always sees the same values.
+pub trait Sampler {
+    fn samples_per_pixel(&self) -> usize;
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample: usize);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    pub samples_per_pixel: usize,
+    pub seed: u64,
+    rng: Pcg32,
+}
+
+impl IndependentSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> Self {
+        Self {
+            samples_per_pixel,
+            seed,
+            rng: Pcg32::new(seed, 0),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn samples_per_pixel(&self) -> usize {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample: usize) {
+        self.rng = Pcg32::for_sample(self.seed, x, y, sample);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.rng.gen::<f64>()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.rng.gen::<f64>(), self.rng.gen::<f64>())
+    }
+}
+
+// Shared bookkeeping for the samplers that decorrelate each dimension with a hash.
+#[derive(Clone, Debug)]
+struct PixelSampleState {
+    seed: u64,
+    x: usize,
+    y: usize,
+    sample: usize,
+    dimension: u64,
+}
+
+impl PixelSampleState {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            x: 0,
+            y: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+
+    fn start(&mut self, x: usize, y: usize, sample: usize) {
+        self.x = x;
+        self.y = y;
+        self.sample = sample;
+        self.dimension = 0;
+    }
+
+    fn next_dimension_hash(&mut self) -> u64 {
+        let hash = mix64(mix64(mix64(self.seed ^ self.x as u64) ^ self.y as u64) ^ self.dimension);
+        self.dimension += 1;
+        hash
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    pub x_strata: usize,
+    pub y_strata: usize,
+    pub jitter: bool,
+    state: PixelSampleState,
+    rng: Pcg32,
+}
+
+impl StratifiedSampler {
+    pub fn new(x_strata: usize, y_strata: usize, jitter: bool, seed: u64) -> Self {
+        Self {
+            x_strata: x_strata.max(1),
+            y_strata: y_strata.max(1),
+            jitter,
+            state: PixelSampleState::new(seed),
+            rng: Pcg32::new(seed, 0),
+        }
+    }
+
+    fn delta(&mut self) -> f64 {
+        if self.jitter {
+            self.rng.gen::<f64>()
+        } else {
+            0.5
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn samples_per_pixel(&self) -> usize {
+        self.x_strata * self.y_strata
+    }
+
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample: usize) {
+        self.state.start(x, y, sample);
+        self.rng = Pcg32::for_sample(self.state.seed, x, y, sample);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let spp = self.samples_per_pixel();
+        let hash = self.state.next_dimension_hash();
+        let stratum = permutation_element(self.state.sample as u32, spp as u32, hash as u32);
+        let delta = self.delta();
+        ((stratum as f64 + delta) / spp as f64).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let spp = self.samples_per_pixel();
+        let hash = self.state.next_dimension_hash();
+        let stratum =
+            permutation_element(self.state.sample as u32, spp as u32, hash as u32) as usize;
+        let (sx, sy) = (stratum % self.x_strata, stratum / self.x_strata);
+        let (dx, dy) = (self.delta(), self.delta());
+        (
+            ((sx as f64 + dx) / self.x_strata as f64).min(ONE_MINUS_EPSILON),
+            ((sy as f64 + dy) / self.y_strata as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// Owen scrambled Halton. Each pixel gets its own scramble, dimensions past the prime table
+// fall back to hashed pseudo-random values.
+#[derive(Clone, Debug)]
+pub struct HaltonSampler {
+    pub samples_per_pixel: usize,
+    state: PixelSampleState,
+}
+
+impl HaltonSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> Self {
+        Self {
+            samples_per_pixel,
+            state: PixelSampleState::new(seed),
+        }
+    }
+
+    fn sample_dimension(&mut self) -> f64 {
+        let dimension = self.state.dimension as usize;
+        let hash = self.state.next_dimension_hash();
+        match PRIMES.get(dimension) {
+            Some(base) => owen_scrambled_radical_inverse(*base, self.state.sample as u64, hash),
+            None => {
+                let bits = mix64(hash ^ self.state.sample as u64) >> 11;
+                bits as f64 / (1u64 << 53) as f64
+            }
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn samples_per_pixel(&self) -> usize {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample: usize) {
+        self.state.start(x, y, sample);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.sample_dimension()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.sample_dimension(), self.sample_dimension())
+    }
+}
+
+// Padded Sobol (Burley 2020): every 1D/2D request uses the first two Sobol dimensions with
+// an index shuffle and Owen scramble seeded by the pixel and dimension.
+#[derive(Clone, Debug)]
+pub struct SobolSampler {
+    pub samples_per_pixel: usize,
+    state: PixelSampleState,
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> Self {
+        Self {
+            samples_per_pixel,
+            state: PixelSampleState::new(seed),
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn samples_per_pixel(&self) -> usize {
+        self.samples_per_pixel
+    }
+
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample: usize) {
+        self.state.start(x, y, sample);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let hash = self.state.next_dimension_hash();
+        let index = nested_uniform_scramble(self.state.sample as u32, hash as u32);
+        let value = nested_uniform_scramble(sobol(index, 0), (hash >> 32) as u32);
+        to_unit(value)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let hash = self.state.next_dimension_hash();
+        let index = nested_uniform_scramble(self.state.sample as u32, hash as u32);
+        let seed = mix64(hash);
+        (
+            to_unit(nested_uniform_scramble(sobol(index, 0), seed as u32)),
+            to_unit(nested_uniform_scramble(
+                sobol(index, 1),
+                (seed >> 32) as u32,
+            )),
+        )
+    }
+}
+
+fn to_unit(x: u32) -> f64 {
+    (x as f64 / 4294967296.).min(ONE_MINUS_EPSILON)
+}
+
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let mut ret = 0;
+    let mut direction = 1u32 << 31;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 == 1 {
+            ret ^= direction;
+        }
+        i >>= 1;
+        direction = match dimension {
+            0 => direction >> 1,
+            // primitive polynomial x + 1
+            _ => direction ^ (direction >> 1),
+        };
+    }
+    ret
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn owen_scrambled_radical_inverse(base: u64, mut a: u64, hash: u64) -> f64 {
+    let inv_base = 1. / base as f64;
+    let mut inv_base_m = 1.;
+    let mut value = 0.;
+    // Digits down to the last one that still changes a double, too many for a u64 in the
+    // larger bases. The digits so far only key the scramble of the next one.
+    let mut prefix = 0u64;
+    while 1. - (base - 1) as f64 * inv_base_m < 1. {
+        let next = a / base;
+        let digit = a - next * base;
+        let digit_hash = mix64(hash ^ prefix);
+        let digit = permutation_element(digit as u32, base as u32, digit_hash as u32) as u64;
+        prefix = prefix.wrapping_mul(base).wrapping_add(digit);
+        inv_base_m *= inv_base;
+        value += digit as f64 * inv_base_m;
+        a = next;
+    }
+    value.min(ONE_MINUS_EPSILON)
+}
+
+// Kensler's hashed permutation: element i of a random permutation of 0..l chosen by p.
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            return (i.wrapping_add(p)) % l;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPP: usize = 16;
+
+    fn samplers() -> Vec<(&'static str, Box<dyn Sampler>)> {
+        vec![
+            ("independent", Box::new(IndependentSampler::new(SPP, 3))),
+            (
+                "stratified",
+                Box::new(StratifiedSampler::new(4, 4, true, 3)),
+            ),
+            ("halton", Box::new(HaltonSampler::new(SPP, 3))),
+            ("sobol", Box::new(SobolSampler::new(SPP, 3))),
+        ]
+    }
+
+    // Every sample of a pixel, as alternating 1D and 2D dimensions.
+    fn pixel_samples(sampler: &mut dyn Sampler, x: usize, y: usize) -> Vec<Vec<(f64, f64)>> {
+        (0..sampler.samples_per_pixel())
+            .map(|sample| {
+                sampler.start_pixel_sample(x, y, sample);
+                (0..40)
+                    .map(|dimension| {
+                        if dimension % 2 == 0 {
+                            (sampler.get_1d(), 0.)
+                        } else {
+                            sampler.get_2d()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        for (name, mut sampler) in samplers() {
+            for (x, y) in [(0, 0), (7, 3), (1000, 1000)] {
+                for sample in pixel_samples(sampler.as_mut(), x, y) {
+                    for (u, v) in sample {
+                        assert!((0. ..1.).contains(&u) && (0. ..1.).contains(&v), "{name}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_samples_are_deterministic() {
+        for (name, mut sampler) in samplers() {
+            let first = pixel_samples(sampler.as_mut(), 5, 9);
+            pixel_samples(sampler.as_mut(), 6, 9);
+            assert_eq!(first, pixel_samples(sampler.as_mut(), 5, 9), "{name}");
+        }
+    }
+
+    #[test]
+    fn one_sample_per_stratum() {
+        for (name, mut sampler) in samplers() {
+            if name != "stratified" && name != "sobol" {
+                continue;
+            }
+            let samples = pixel_samples(sampler.as_mut(), 2, 4);
+            for dimension in 0..samples[0].len() {
+                let mut strata = [false; SPP];
+                for sample in samples.iter() {
+                    let (u, v) = sample[dimension];
+                    let stratum = if dimension % 2 == 0 {
+                        (u * 16.) as usize
+                    } else {
+                        (v * 4.) as usize * 4 + (u * 4.) as usize
+                    };
+                    assert!(!strata[stratum], "{name} dimension {dimension}");
+                    strata[stratum] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_without_strata_takes_one_sample() {
+        let mut sampler = StratifiedSampler::new(0, 0, true, 1);
+        assert_eq!(sampler.samples_per_pixel(), 1);
+        sampler.start_pixel_sample(0, 0, 0);
+        assert!((0. ..1.).contains(&sampler.get_1d()));
+    }
+}
@@ -0,0 +1,49 @@
+// Monte Carlo checks shared by the BSDF tests. Directions follow the integrator's convention:
+// `wo` is the direction the ray travels in, towards the surface.
+use crate::math;
+use crate::math::V3;
+use crate::path_tracer::sampler::{IndependentSampler, Sampler};
+use crate::path_tracer::{sample_sphere, BSDFSample, BSDF};
+use std::f64::consts::PI;
+
+// A ray coming down onto the surface at `cos_theta` to the normal.
+pub fn incoming(cos_theta: f64) -> V3 {
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    math::v(sin_theta, 0., -cos_theta)
+}
+
+// Every non-specular sample `sample_f` draws from `wo`.
+pub fn samples(bsdf: &dyn BSDF, wo: V3, count: usize) -> Vec<BSDFSample> {
+    let mut sampler = IndependentSampler::new(count, 7);
+    (0..count)
+        .map(|i| {
+            sampler.start_pixel_sample(0, 0, i);
+            bsdf.sample_f(wo, &mut sampler)
+        })
+        .filter(|sample| !sample.specular)
+        .collect()
+}
+
+// Share of the light arriving from -wo that is scattered, per channel: the mean of
+// f |cos| / pdf over `count` samples, failed ones counting as black.
+pub fn albedo(bsdf: &dyn BSDF, wo: V3, count: usize) -> V3 {
+    let sum = samples(bsdf, wo, count)
+        .iter()
+        .filter(|sample| sample.pdf > 0.)
+        .fold(math::O, |sum, sample| {
+            sum + (sample.wi.z.abs() / sample.pdf) * sample.f
+        });
+    (1. / count as f64) * sum
+}
+
+// Integral of `pdf` over the sphere of directions, from uniformly distributed ones.
+pub fn pdf_integral(pdf: impl Fn(V3) -> f64, count: usize) -> f64 {
+    let mut sampler = IndependentSampler::new(count, 11);
+    let sum: f64 = (0..count)
+        .map(|i| {
+            sampler.start_pixel_sample(0, 0, i);
+            pdf(sample_sphere(&mut sampler))
+        })
+        .sum();
+    4. * PI * sum / count as f64
+}
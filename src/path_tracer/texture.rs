@@ -0,0 +1,325 @@
+use crate::color::{luminance, srgb_eotf};
+use crate::math;
+use crate::math::{Intersection, V3};
+use crate::noise::Noise;
+use crate::path_tracer::sampler::Sampler;
+use crate::path_tracer::{BSDFSample, BSDF};
+use std::ops::{Add, Mul};
+use std::sync::Arc;
+
+// Value of a material parameter at a hit, colours being `V3` and scalars `f64`.
+pub trait Texture<T>: Send + Sync {
+    fn evaluate(&self, hit: &Intersection) -> T;
+}
+
+impl<T, X: Texture<T> + ?Sized> Texture<T> for Arc<X> {
+    fn evaluate(&self, hit: &Intersection) -> T {
+        (**self).evaluate(hit)
+    }
+}
+
+// Squares of `even` and `odd` alternating `scale` times per unit of uv.
+#[derive(Clone, Copy, Debug)]
+pub struct Checkerboard<T> {
+    pub even: T,
+    pub odd: T,
+    pub scale: f64,
+}
+
+impl<T: Copy + Send + Sync> Texture<T> for Checkerboard<T> {
+    fn evaluate(&self, hit: &Intersection) -> T {
+        let (u, v) = hit.uv;
+        let square = (u * self.scale).floor() as i64 + (v * self.scale).floor() as i64;
+        if square.rem_euclid(2) == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+// Blend from `start` at the point `from` to `end` at `to`, constant beyond either end.
+#[derive(Clone, Copy, Debug)]
+pub struct Gradient<T> {
+    pub start: T,
+    pub end: T,
+    pub from: V3,
+    pub to: V3,
+}
+
+impl<T> Texture<T> for Gradient<T>
+where
+    T: Copy + Send + Sync + Add<Output = T>,
+    f64: Mul<T, Output = T>,
+{
+    fn evaluate(&self, hit: &Intersection) -> T {
+        let axis = self.to - self.from;
+        let t = (math::dot(&(hit.x - self.from), &axis) / math::dot(&axis, &axis)).clamp(0., 1.);
+        (1. - t) * self.start + t * self.end
+    }
+}
+
+// Solid noise read at the hit in object space, `start` where it is -1 and `end` where it is 1.
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseTexture<N, T> {
+    pub noise: N,
+    pub start: T,
+    pub end: T,
+}
+
+impl<N: Noise, T> Texture<T> for NoiseTexture<N, T>
+where
+    T: Copy + Send + Sync + Add<Output = T>,
+    f64: Mul<T, Output = T>,
+{
+    fn evaluate(&self, hit: &Intersection) -> T {
+        let t = self.noise.noise(hit.x).mul_add(0.5, 0.5).clamp(0., 1.);
+        (1. - t) * self.start + t * self.end
+    }
+}
+
+// What an image texture shows outside [0, 1] uv.
+#[derive(Clone, Copy, Debug)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Wrap {
+    fn index(self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+        };
+        i as usize
+    }
+}
+
+// Bilinearly filtered image, v going up the image. Scalar lookups read its luminance.
+#[derive(Clone, Debug)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    texels: Vec<V3>,
+    pub wrap: Wrap,
+}
+
+impl ImageTexture {
+    // Colour maps are usually sRGB encoded, data (roughness, masks) and HDR images linear.
+    pub fn open(path: &str, wrap: Wrap, srgb: bool) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.to_rgb32f();
+        let decode = |x: f32| if srgb { srgb_eotf(x as f64) } else { x as f64 };
+        let texels = image
+            .pixels()
+            .map(|p| math::v(decode(p.0[0]), decode(p.0[1]), decode(p.0[2])))
+            .collect();
+        Ok(Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels,
+            wrap,
+        })
+    }
+
+    fn texel(&self, x: i64, y: i64) -> V3 {
+        let x = self.wrap.index(x, self.width);
+        let y = self.wrap.index(y, self.height);
+        self.texels[y * self.width + x]
+    }
+}
+
+impl Texture<V3> for ImageTexture {
+    fn evaluate(&self, hit: &Intersection) -> V3 {
+        let (u, v) = hit.uv;
+        // Texel centres sit at half-integer coordinates.
+        let x = u * self.width as f64 - 0.5;
+        let y = (1. - v) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        (1. - ty) * ((1. - tx) * self.texel(x0, y0) + tx * self.texel(x0 + 1, y0))
+            + ty * ((1. - tx) * self.texel(x0, y0 + 1) + tx * self.texel(x0 + 1, y0 + 1))
+    }
+}
+
+impl Texture<f64> for ImageTexture {
+    fn evaluate(&self, hit: &Intersection) -> f64 {
+        luminance(Texture::<V3>::evaluate(self, hit))
+    }
+}
+
+type MaterialAt = dyn Fn(&Intersection) -> Arc<dyn BSDF> + Send + Sync;
+
+// Material with parameters read from textures, built by `material` for every hit, e.g.
+// `Textured::new(move |hit| Lambertian { reflectance: image.evaluate(hit) })`.
+pub struct Textured {
+    material: Box<MaterialAt>,
+}
+
+impl Textured {
+    pub fn new<B: BSDF + 'static>(
+        material: impl Fn(&Intersection) -> B + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            material: Box::new(move |hit| Arc::new(material(hit))),
+        }
+    }
+}
+
+// A `Solid` replaces it by the material at each hit, on its own it is black.
+impl BSDF for Textured {
+    fn sample_wi(&self, _wo: V3, _sampler: &mut dyn Sampler) -> (f64, V3) {
+        (0., math::B3)
+    }
+
+    fn bsdf(&self, _wo: V3, _wi: V3) -> V3 {
+        math::O
+    }
+
+    fn radiance(&self, _wo: V3) -> V3 {
+        math::O
+    }
+
+    fn pdf(&self, _wo: V3, _wi: V3) -> f64 {
+        0.
+    }
+
+    fn at(&self, hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        let bsdf = (self.material)(hit);
+        // Nested varying materials, such as a mix of textured layers, resolve in turn.
+        Some(bsdf.at(hit).unwrap_or(bsdf))
+    }
+
+    fn sample_f(&self, _wo: V3, _sampler: &mut dyn Sampler) -> BSDFSample {
+        BSDFSample {
+            wi: math::B3,
+            pdf: 0.,
+            f: math::O,
+            specular: false,
+        }
+    }
+}
+
+// Step in uv of the finite differences of bump maps.
+const BUMP_DELTA: f64 = 5e-4;
+
+// How a map tilts the shading normal.
+#[derive(Clone)]
+pub enum NormalMap {
+    // Tangent space normals, read as linear RGB with x along the tangent, y along the
+    // bitangent and z along the normal.
+    Tangent(Arc<dyn Texture<V3>>),
+    // Heights, displacing the surface along its normal by `scale` times the height, in
+    // object space units.
+    Bump {
+        height: Arc<dyn Texture<f64>>,
+        scale: f64,
+    },
+}
+
+// `material` with the shading normal tilted by `map`.
+pub struct NormalMapped {
+    pub material: Arc<dyn BSDF>,
+    pub map: NormalMap,
+}
+
+impl NormalMapped {
+    // Tilted normal at `hit`, None where the map leaves it alone.
+    fn normal(&self, hit: &Intersection) -> Option<V3> {
+        match &self.map {
+            NormalMap::Tangent(texture) => {
+                let local = 2. * texture.evaluate(hit) - math::v(1., 1., 1.);
+                // Maps never point below the surface, a broken texel leaves the normal alone.
+                if local.z <= 0. {
+                    return None;
+                }
+                let tangent = hit.dpdu - math::dot(&hit.dpdu, &hit.n) * hit.n;
+                let tangent = if math::abs2(&tangent) > 1e-12 {
+                    math::normalize(&tangent)
+                } else {
+                    hit.s
+                };
+                // Mirrored uv islands run v the other way round.
+                let bitangent = math::cross(&hit.n, &tangent);
+                let bitangent = if math::dot(&bitangent, &hit.dpdv) < 0. {
+                    -bitangent
+                } else {
+                    bitangent
+                };
+                let n = local.x * tangent + local.y * bitangent + local.z * hit.n;
+                Some(math::normalize(&n))
+            }
+            NormalMap::Bump { height, scale } => {
+                // The displaced surface's derivatives along u and v (Blinn 1978), leaving
+                // out the change in the normal itself.
+                let step = |du: f64, dv: f64| Intersection {
+                    x: hit.x + du * hit.dpdu + dv * hit.dpdv,
+                    uv: (hit.uv.0 + du, hit.uv.1 + dv),
+                    ..hit.clone()
+                };
+                let h = height.evaluate(hit);
+                let dh_du = (height.evaluate(&step(BUMP_DELTA, 0.)) - h) / BUMP_DELTA;
+                let dh_dv = (height.evaluate(&step(0., BUMP_DELTA)) - h) / BUMP_DELTA;
+                let dpdu = hit.dpdu + (scale * dh_du) * hit.n;
+                let dpdv = hit.dpdv + (scale * dh_dv) * hit.n;
+                let n = math::cross(&dpdu, &dpdv);
+                if math::abs2(&n) == 0. {
+                    return None;
+                }
+                // Left handed uvs give the normal of the other side.
+                let n = math::normalize(&n);
+                Some(if math::dot(&n, &hit.n) < 0. { -n } else { n })
+            }
+        }
+    }
+}
+
+impl BSDF for NormalMapped {
+    fn sample_wi(&self, wo: V3, sampler: &mut dyn Sampler) -> (f64, V3) {
+        self.material.sample_wi(wo, sampler)
+    }
+
+    fn bsdf(&self, wo: V3, wi: V3) -> V3 {
+        self.material.bsdf(wo, wi)
+    }
+
+    fn radiance(&self, wo: V3) -> V3 {
+        self.material.radiance(wo)
+    }
+
+    fn pdf(&self, wo: V3, wi: V3) -> f64 {
+        self.material.pdf(wo, wi)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.material.is_specular()
+    }
+
+    fn at(&self, hit: &Intersection) -> Option<Arc<dyn BSDF>> {
+        self.material.at(hit)
+    }
+
+    fn perturb(&self, hit: &mut Intersection) {
+        self.material.perturb(hit);
+        let Some(n) = self.normal(hit) else {
+            return;
+        };
+        let s = hit.s - math::dot(&hit.s, &n) * n;
+        hit.n = n;
+        hit.s = math::normalize(&s);
+    }
+
+    fn sample_f(&self, wo: V3, sampler: &mut dyn Sampler) -> BSDFSample {
+        self.material.sample_f(wo, sampler)
+    }
+}
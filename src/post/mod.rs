@@ -0,0 +1,211 @@
+use crate::math;
+use crate::math::{v, V3};
+
+// Effects run on the linear HDR image, before tone mapping.
+pub trait PostEffect: Send + Sync {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3>;
+}
+
+pub struct Pipeline {
+    pub effects: Vec<Box<dyn PostEffect>>,
+}
+
+impl PostEffect for Pipeline {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3> {
+        let mut ret = pixels.to_vec();
+        for effect in self.effects.iter() {
+            ret = effect.apply(width, height, &ret);
+        }
+        ret
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Bloom {
+    pub threshold: f64,
+    pub intensity: f64,
+    pub radius: f64,
+}
+
+// Star shaped streaks around bright pixels, like the diffraction from aperture blades.
+#[derive(Clone, Copy, Debug)]
+pub struct Glare {
+    pub threshold: f64,
+    pub intensity: f64,
+    pub length: f64,
+    pub streaks: usize,
+    pub rotation: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vignette {
+    pub strength: f64,
+    pub falloff: f64,
+}
+
+// Lateral chromatic aberration: red is magnified by e^strength and blue shrunk by the same
+// factor, relative to the image center. Small strengths are about the relative size change.
+#[derive(Clone, Copy, Debug)]
+pub struct ChromaticAberration {
+    pub strength: f64,
+}
+
+fn bright_pass(pixels: &[V3], threshold: f64) -> Vec<V3> {
+    pixels
+        .iter()
+        .map(|p| {
+            let brightness = p.x.max(p.y).max(p.z);
+            if brightness <= threshold {
+                math::O
+            } else {
+                ((brightness - threshold) / brightness) * *p
+            }
+        })
+        .collect()
+}
+
+fn gaussian_kernel(sigma: f64) -> Vec<f64> {
+    let r = (3. * sigma).ceil() as i64;
+    let kernel: Vec<f64> = (-r..=r)
+        .map(|i| (-(i * i) as f64 / (2. * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    kernel.into_iter().map(|k| k / sum).collect()
+}
+
+fn blur_pass(
+    width: usize,
+    height: usize,
+    pixels: &[V3],
+    kernel: &[f64],
+    horizontal: bool,
+) -> Vec<V3> {
+    let r = (kernel.len() / 2) as i64;
+    let mut ret = vec![math::O; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = math::O;
+            for (k, weight) in kernel.iter().enumerate() {
+                let offset = k as i64 - r;
+                let (sx, sy) = if horizontal {
+                    ((x as i64 + offset).clamp(0, width as i64 - 1) as usize, y)
+                } else {
+                    (x, (y as i64 + offset).clamp(0, height as i64 - 1) as usize)
+                };
+                sum = sum + *weight * pixels[sx + sy * width];
+            }
+            ret[x + y * width] = sum;
+        }
+    }
+    ret
+}
+
+pub fn gaussian_blur(width: usize, height: usize, pixels: &[V3], sigma: f64) -> Vec<V3> {
+    if sigma <= 0. {
+        return pixels.to_vec();
+    }
+    let kernel = gaussian_kernel(sigma);
+    let horizontal = blur_pass(width, height, pixels, &kernel, true);
+    blur_pass(width, height, &horizontal, &kernel, false)
+}
+
+impl PostEffect for Bloom {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3> {
+        let bright = bright_pass(pixels, self.threshold);
+        // A wide and a narrow blur together give a tight core with a long soft tail.
+        let wide = gaussian_blur(width, height, &bright, self.radius / 3.);
+        let narrow = gaussian_blur(width, height, &bright, self.radius / 12.);
+        pixels
+            .iter()
+            .zip(wide.iter().zip(narrow.iter()))
+            .map(|(p, (w, n))| *p + (0.5 * self.intensity) * (*w + *n))
+            .collect()
+    }
+}
+
+impl PostEffect for Glare {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3> {
+        let bright = bright_pass(pixels, self.threshold);
+        let mut ret = pixels.to_vec();
+        let steps = self.length.ceil() as usize;
+        let directions: Vec<(f64, f64)> = (0..self.streaks)
+            .map(|i| {
+                let angle = self.rotation + std::f64::consts::TAU * i as f64 / self.streaks as f64;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        let norm: f64 = (1..=steps)
+            .map(|i| (-4. * i as f64 / self.length).exp())
+            .sum();
+        let scale = self.intensity / (norm * self.streaks as f64).max(1.);
+        for y in 0..height {
+            for x in 0..width {
+                let b = bright[x + y * width];
+                if b.x <= 0. && b.y <= 0. && b.z <= 0. {
+                    continue;
+                }
+                for (dx, dy) in directions.iter() {
+                    for i in 1..=steps {
+                        let sx = (x as f64 + 0.5 + dx * i as f64).floor();
+                        let sy = (y as f64 + 0.5 + dy * i as f64).floor();
+                        if sx < 0. || sy < 0. || sx >= width as f64 || sy >= height as f64 {
+                            break;
+                        }
+                        let falloff = (-4. * i as f64 / self.length).exp();
+                        let index = sx as usize + sy as usize * width;
+                        ret[index] = ret[index] + (scale * falloff) * b;
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3> {
+        let (cx, cy) = (0.5 * width as f64, 0.5 * height as f64);
+        let corner = (cx * cx + cy * cy).sqrt();
+        pixels
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let dx = (i % width) as f64 + 0.5 - cx;
+                let dy = (i / width) as f64 + 0.5 - cy;
+                let r = (dx * dx + dy * dy).sqrt() / corner;
+                (1. - self.strength * r.powf(self.falloff)).max(0.) * *p
+            })
+            .collect()
+    }
+}
+
+fn sample_bilinear(width: usize, height: usize, pixels: &[V3], x: f64, y: f64) -> V3 {
+    let x = (x - 0.5).clamp(0., width as f64 - 1.);
+    let y = (y - 0.5).clamp(0., height as f64 - 1.);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let at = |x: usize, y: usize| pixels[x + y * width];
+    (1. - fy) * ((1. - fx) * at(x0, y0) + fx * at(x1, y0))
+        + fy * ((1. - fx) * at(x0, y1) + fx * at(x1, y1))
+}
+
+impl PostEffect for ChromaticAberration {
+    fn apply(&self, width: usize, height: usize, pixels: &[V3]) -> Vec<V3> {
+        let (cx, cy) = (0.5 * width as f64, 0.5 * height as f64);
+        (0..width * height)
+            .map(|i| {
+                let x = (i % width) as f64 + 0.5 - cx;
+                let y = (i / width) as f64 + 0.5 - cy;
+                let channel_at = |scale: f64| {
+                    sample_bilinear(width, height, pixels, cx + x / scale, cy + y / scale)
+                };
+                v(
+                    channel_at(self.strength.exp()).x,
+                    pixels[i].y,
+                    channel_at((-self.strength).exp()).z,
+                )
+            })
+            .collect()
+    }
+}